@@ -1,26 +1,67 @@
 CREATE TABLE Users (
     user_id SERIAL PRIMARY KEY,
     oauth_type VARCHAR(255),
-    sub VARCHAR(255) UNIQUE,
+    sub VARCHAR(255),
     name VARCHAR(255),
     picture VARCHAR(255),
+    password_hash VARCHAR(255),
     number_of_files INTEGER DEFAULT 0,
-    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+    total_bytes BIGINT NOT NULL DEFAULT 0,
+    max_files INTEGER,
+    max_bytes BIGINT,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    UNIQUE (oauth_type, sub)
 );
 
 CREATE INDEX idx_users_sub ON Users(sub);
 
+CREATE TABLE Auth_Sessions (
+    session_id VARCHAR(64) PRIMARY KEY,
+    user_id INTEGER REFERENCES Users(user_id) ON DELETE CASCADE,
+    refresh_hash VARCHAR(64) NOT NULL UNIQUE,
+    expires_at TIMESTAMP NOT NULL,
+    revoked BOOLEAN NOT NULL DEFAULT FALSE,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX idx_auth_sessions_user_id ON Auth_Sessions(user_id);
+
+CREATE TABLE Blobs (
+    blob_id SERIAL PRIMARY KEY,
+    storage_key VARCHAR(255) NOT NULL UNIQUE,
+    source_url VARCHAR(255) UNIQUE,
+    content_hash VARCHAR(64) NOT NULL UNIQUE,
+    size_bytes BIGINT NOT NULL DEFAULT 0,
+    loudness_lufs DOUBLE PRECISION,
+    duration_ms INTEGER,
+    artist VARCHAR(255),
+    thumbnail TEXT,
+    original_format VARCHAR(32),
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
 CREATE TABLE Files (
     file_id SERIAL PRIMARY KEY,
     user_id INTEGER REFERENCES Users(user_id) ON DELETE CASCADE,
     url VARCHAR(255) NOT NULL,
-    uuid VARCHAR(255) NOT NULL UNIQUE,
+    uuid VARCHAR(255) NOT NULL,
+    blob_id INTEGER REFERENCES Blobs(blob_id),
     name VARCHAR(255),
     name_tsv tsvector,
-    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+    loudness_lufs DOUBLE PRECISION,
+    duration_ms INTEGER,
+    artist VARCHAR(255),
+    thumbnail TEXT,
+    original_format VARCHAR(32),
+    size_bytes BIGINT NOT NULL DEFAULT 0,
+    is_public BOOLEAN NOT NULL DEFAULT FALSE,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    UNIQUE (user_id, uuid)
 );
 
 CREATE INDEX idx_files_name_tsv ON Files USING GIN(name_tsv);
+CREATE INDEX idx_files_uuid ON Files(uuid);
+CREATE INDEX idx_files_blob_id ON Files(blob_id);
 
 CREATE TABLE Sessions (
     session_id SERIAL PRIMARY KEY,
@@ -43,4 +84,48 @@ $$ LANGUAGE plpgsql;
 
 CREATE TRIGGER trg_files_tsv_update
 BEFORE INSERT OR UPDATE OF name ON Files
-FOR EACH ROW EXECUTE FUNCTION files_tsv_trigger();
\ No newline at end of file
+FOR EACH ROW EXECUTE FUNCTION files_tsv_trigger();
+
+CREATE TABLE Download_Jobs (
+    job_id SERIAL PRIMARY KEY,
+    user_id INTEGER REFERENCES Users(user_id) ON DELETE CASCADE,
+    url VARCHAR(255) NOT NULL,
+    title VARCHAR(255) NOT NULL,
+    status VARCHAR(16) NOT NULL DEFAULT 'queued',
+    attempts INTEGER NOT NULL DEFAULT 0,
+    error_code VARCHAR(64),
+    error TEXT,
+    file_uuid VARCHAR(255),
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX idx_download_jobs_user_id ON Download_Jobs(user_id);
+CREATE INDEX idx_download_jobs_status ON Download_Jobs(status);
+
+CREATE TABLE Playlists (
+    playlist_id SERIAL PRIMARY KEY,
+    user_id INTEGER REFERENCES Users(user_id) ON DELETE CASCADE,
+    name VARCHAR(255) NOT NULL,
+    description TEXT,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX idx_playlists_user_id ON Playlists(user_id);
+
+CREATE TABLE Playlist_Items (
+    item_id SERIAL PRIMARY KEY,
+    playlist_id INTEGER REFERENCES Playlists(playlist_id) ON DELETE CASCADE,
+    position INTEGER NOT NULL,
+    file_uuid VARCHAR(255) NOT NULL,
+    title VARCHAR(255) NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX idx_playlist_items_playlist_id ON Playlist_Items(playlist_id, position);
+
+-- downloads started by a playlist import add their file to it once they finish
+ALTER TABLE Download_Jobs
+    ADD COLUMN playlist_id INTEGER REFERENCES Playlists(playlist_id) ON DELETE SET NULL,
+    ADD COLUMN playlist_position INTEGER;
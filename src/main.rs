@@ -37,6 +37,7 @@ mod db;
 mod utils;
 mod middlewares;
 mod ctx;
+mod storage;
 
 // import error.rs module
 use crate::media::file_manager::FileManager;
@@ -50,6 +51,13 @@ async fn main() -> Result<()> {
     // initialize session controller
     let mc = Arc::new(SessionController::new().await?);
     let pool = db::establish_connection().await?;
+    let identity_providers = middlewares::identity::IdentityProviders::from_env()?;
+    let token_cache = middlewares::token_cache::TokenCache::from_env();
+    let session_tokens = middlewares::session_token::SessionTokens::from_env()?;
+
+    // downloads left unfinished by the last run are started again
+    let job_runner = media::jobs::JobRunner::new(pool.clone(), mc.get_file_manager().await?);
+    job_runner.resume_pending().await?;
 
     let api_cors = CorsLayer::new()
         .allow_origin(AllowOrigin::mirror_request())
@@ -59,6 +67,7 @@ async fn main() -> Result<()> {
         .allow_credentials(true);
 
     let routes_control = routes::routes_control::routes(mc.clone())
+        .merge(routes::routes_playlists::routes(mc.clone()))
         .route_layer(middleware::from_fn(middlewares::mw::mw_require_auth))
         .layer(middleware::from_fn_with_state(
             mc.clone(),
@@ -78,8 +87,13 @@ async fn main() -> Result<()> {
         .nest("/hello", routes_hello()) 
         .nest("/api", routes_control)
         .nest("/session", routes_session)
+        .nest("/auth", routes::routes_auth::routes())
         .layer(CookieManagerLayer::new())
         .layer(Extension(pool))
+        .layer(Extension(identity_providers))
+        .layer(Extension(token_cache))
+        .layer(Extension(session_tokens))
+        .layer(Extension(job_runner))
         .layer(middleware::map_response(main_response_mapper))
         .layer(api_cors)
         .fallback_service(routes_static());
@@ -96,6 +110,31 @@ async fn main() -> Result<()> {
 
 async fn main_response_mapper(res: Response) -> Response {
     println!("->> {:<12} - main_response_mapper", "Mapper");
+    let req_uuid = uuid::Uuid::new_v4().to_string();
+
+    // errors are rendered here so the client only ever sees the ClientError side
+    let error_response = res.extensions().get::<Error>().map(|error| {
+        let (status_code, client_error) = error.client_status_and_error();
+        println!("->> {:<12} - {req_uuid} - {error:?}", "ERROR");
+
+        let client_error_json = serde_json::to_value(&client_error).unwrap_or(serde_json::Value::Null);
+        let body = serde_json::json!({
+            "error": {
+                "code": client_error.code(),
+                "message": client_error.message(),
+                "details": client_error_json.get("details"),
+                "req_uuid": req_uuid,
+            }
+        });
+
+        (status_code, axum::Json(body)).into_response()
+    });
+
+    let mut res = error_response.unwrap_or(res);
+    if let Ok(value) = axum::http::HeaderValue::from_str(&req_uuid) {
+        res.headers_mut().insert("x-request-id", value);
+    }
+
     println!();
     res
 }
@@ -0,0 +1,278 @@
+use crate::utils::error::{ Error, Result };
+use crate::media::file_manager::{ FMDownloadParams, TrackMetadata, FFMPEG_PATH };
+use crate::media::quota::Quota;
+use sha2::{ Digest, Sha256 };
+use sqlx::{ PgPool, Postgres, Row, Transaction };
+use std::process::Stdio;
+use tokio::io::AsyncReadExt;
+use tokio::process::Command;
+use url::Url;
+
+// query parameters that only track where a link was shared from
+const TRACKING_PARAMS: [&str; 6] = ["si", "feature", "pp", "list", "index", "t"];
+
+/// Stored audio shared by every files row that points at it. Blobs are found by the
+/// normalized source url before downloading and by content hash after converting.
+#[derive(Clone, Debug)]
+pub struct Blob {
+    pub blob_id: i32,
+    pub storage_key: String,
+}
+
+/// Same source, same string: youtube links become "youtube:<video id>", other urls
+/// lose their fragment, tracking parameters and trailing slash. Uploads have no source.
+pub fn normalize_source_url(raw: &str) -> Option<String> {
+    let url = Url::parse(raw.trim()).ok()?;
+    if url.scheme() != "http" && url.scheme() != "https" {
+        return None;
+    }
+
+    let host = url.host_str()?.trim_start_matches("www.").trim_start_matches("m.").to_lowercase();
+    let video_id = match host.as_str() {
+        "youtu.be" => url.path_segments().and_then(|mut s| s.next()).map(String::from),
+        "youtube.com" | "music.youtube.com" => url
+            .query_pairs()
+            .find(|(k, _)| k == "v")
+            .map(|(_, v)| v.to_string())
+            .or_else(|| {
+                // /shorts/<id> and /embed/<id>
+                let segments: Vec<&str> = url.path_segments()?.collect();
+                match segments.as_slice() {
+                    ["shorts", id, ..] | ["embed", id, ..] => Some(id.to_string()),
+                    _ => None,
+                }
+            }),
+        _ => None,
+    };
+
+    if let Some(id) = video_id.filter(|id| !id.is_empty()) {
+        return Some(format!("youtube:{}", id));
+    }
+
+    let mut params: Vec<(String, String)> = url
+        .query_pairs()
+        .filter(|(k, _)| !k.starts_with("utm_") && !TRACKING_PARAMS.contains(&k.as_ref()))
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+    params.sort();
+
+    let mut normalized = format!("{}{}", host, url.path().trim_end_matches('/'));
+    if !params.is_empty() {
+        let query: Vec<String> = params.iter().map(|(k, v)| format!("{}={}", k, v)).collect();
+        normalized = format!("{}?{}", normalized, query.join("&"));
+    }
+    Some(normalized)
+}
+
+// everything known about a converted file when it is first stored
+pub struct NewBlob<'a> {
+    pub storage_key: &'a str,
+    pub source_url: Option<&'a str>,
+    pub content_hash: &'a str,
+    pub size_bytes: i64,
+    pub loudness_lufs: Option<f64>,
+    pub metadata: &'a TrackMetadata,
+}
+
+// sha256 of the decoded audio, hex encoded. The ogg muxer writes a random stream
+// serial and encoder tags, so the bytes of two encodes of the same audio never match
+pub async fn content_hash(file_path: &str, source_url: &str) -> Result<String> {
+    let mut child = Command::new(FFMPEG_PATH)
+        .args(["-hide_banner", "-nostats", "-v", "error"])
+        .args(["-i", file_path])
+        .args(["-map", "0:a:0", "-f", "s16le", "-ac", "2", "-ar", "48000", "-"])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .kill_on_drop(true)
+        .spawn()?;
+
+    let mut pcm = child.stdout.take().ok_or(Error::ConversionFailed { url: source_url.to_string() })?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; 64 * 1024];
+
+    loop {
+        let read = pcm.read(&mut buffer).await?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+    }
+
+    if !child.wait().await?.success() {
+        return Err(Error::ConversionFailed { url: source_url.to_string() });
+    }
+
+    Ok(hasher.finalize().iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+fn db_error(e: sqlx::Error) -> Error {
+    Error::DatabaseWriteError { msg: e.to_string() }
+}
+
+pub async fn find_by_source(pool: &PgPool, source_url: &str) -> Result<Option<Blob>> {
+    let row = sqlx::query("SELECT blob_id, storage_key FROM blobs WHERE source_url = $1")
+        .bind(source_url)
+        .fetch_optional(pool)
+        .await
+        .map_err(db_error)?;
+
+    Ok(row.map(|row| Blob { blob_id: row.get("blob_id"), storage_key: row.get("storage_key") }))
+}
+
+pub async fn find_by_hash(pool: &PgPool, content_hash: &str) -> Result<Option<Blob>> {
+    let row = sqlx::query("SELECT blob_id, storage_key FROM blobs WHERE content_hash = $1")
+        .bind(content_hash)
+        .fetch_optional(pool)
+        .await
+        .map_err(db_error)?;
+
+    Ok(row.map(|row| Blob { blob_id: row.get("blob_id"), storage_key: row.get("storage_key") }))
+}
+
+/// Records a freshly stored object. When another download of the same source or content
+/// won the race, its blob is returned instead and the caller drops its own object.
+pub async fn insert(pool: &PgPool, new: NewBlob<'_>) -> Result<Blob> {
+    let NewBlob { storage_key, source_url, content_hash, size_bytes, loudness_lufs, metadata } = new;
+
+    let row = sqlx::query(
+        "
+        INSERT INTO blobs (
+            storage_key, source_url, content_hash, size_bytes, loudness_lufs,
+            duration_ms, artist, thumbnail, original_format
+        )
+        VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+        ON CONFLICT DO NOTHING
+        RETURNING blob_id, storage_key
+        ")
+        .bind(storage_key)
+        .bind(source_url)
+        .bind(content_hash)
+        .bind(size_bytes)
+        .bind(loudness_lufs)
+        .bind(metadata.duration_ms)
+        .bind(&metadata.artist)
+        .bind(&metadata.thumbnail)
+        .bind(&metadata.original_format)
+        .fetch_optional(pool)
+        .await
+        .map_err(db_error)?;
+
+    if let Some(row) = row {
+        return Ok(Blob { blob_id: row.get("blob_id"), storage_key: row.get("storage_key") });
+    }
+
+    let existing = match find_by_hash(pool, content_hash).await? {
+        Some(blob) => Some(blob),
+        None => match source_url {
+            Some(source_url) => find_by_source(pool, source_url).await?,
+            None => None,
+        },
+    };
+    existing.ok_or(Error::DatabaseWriteError { msg: "blob conflict without a matching blob".to_string() })
+}
+
+/// Adds a files row of the user for the blob, counted against their quota like any other file.
+pub async fn link(pool: &PgPool, quota: &Quota, params: &FMDownloadParams, blob: &Blob) -> Result<()> {
+    let user_id = params.userid.parse::<i32>().map_err(|_| Error::AuthFailInvalidToken)?;
+    let mut tx = pool.begin().await.map_err(db_error)?;
+
+    // holds off unlink from removing the blob while it gains a reference
+    let size_bytes: i64 = sqlx::query("SELECT size_bytes FROM blobs WHERE blob_id = $1 FOR UPDATE")
+        .bind(blob.blob_id)
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(db_error)?
+        .ok_or(Error::ContentNotFound { msg: blob.storage_key.clone() })?
+        .get("size_bytes");
+
+    quota.usage_for_update(&mut tx, user_id).await?.check(size_bytes)?;
+
+    let inserted = sqlx::query(
+        "
+        INSERT INTO files (
+            user_id, url, uuid, name, blob_id, loudness_lufs,
+            duration_ms, artist, thumbnail, original_format, size_bytes
+        )
+        SELECT $1, $2, storage_key, $3, blob_id, loudness_lufs,
+               duration_ms, artist, thumbnail, original_format, size_bytes
+        FROM blobs WHERE blob_id = $4
+        ON CONFLICT (user_id, uuid) DO NOTHING
+        ")
+        .bind(user_id)
+        .bind(&params.url)
+        .bind(&params.title)
+        .bind(blob.blob_id)
+        .execute(&mut *tx)
+        .await
+        .map_err(db_error)?;
+
+    // the user already has this content under another url
+    if inserted.rows_affected() == 0 {
+        return Err(Error::DuplicateContent { msg: params.url.clone() });
+    }
+
+    Quota::add_usage(&mut tx, user_id, 1, size_bytes).await?;
+    tx.commit().await.map_err(db_error)?;
+    Ok(())
+}
+
+/// Removes the user's files row and gives its size back to their quota. Returns the
+/// storage key when that was the last reference and the object should be deleted.
+pub async fn unlink(pool: &PgPool, user_id: i32, uuid: &str) -> Result<Option<String>> {
+    let mut tx = pool.begin().await.map_err(db_error)?;
+
+    let row = sqlx::query("DELETE FROM files WHERE uuid = $1 AND user_id = $2 RETURNING blob_id, size_bytes")
+        .bind(uuid)
+        .bind(user_id)
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(db_error)?;
+
+    let Some(row) = row else {
+        return Ok(None);
+    };
+
+    Quota::add_usage(&mut tx, user_id, -1, -row.get::<i64, &str>("size_bytes")).await?;
+
+    // playlist items only point at the key, they go with the file
+    sqlx::query(
+        "DELETE FROM playlist_items i USING playlists p
+         WHERE i.playlist_id = p.playlist_id AND p.user_id = $1 AND i.file_uuid = $2"
+    )
+    .bind(user_id)
+    .bind(uuid)
+    .execute(&mut *tx)
+    .await
+    .map_err(db_error)?;
+
+    let blob_id: Option<i32> = row.get("blob_id");
+    let orphaned = match blob_id {
+        Some(blob_id) => remove_if_unreferenced(&mut tx, blob_id).await?,
+        // files stored before blobs existed are owned by their row alone
+        None => Some(uuid.to_string()),
+    };
+
+    tx.commit().await.map_err(db_error)?;
+    Ok(orphaned)
+}
+
+/// Drops the blob row once no files row points at it, returning its storage key.
+pub async fn remove_if_unreferenced(tx: &mut Transaction<'_, Postgres>, blob_id: i32) -> Result<Option<String>> {
+    sqlx::query("SELECT blob_id FROM blobs WHERE blob_id = $1 FOR UPDATE")
+        .bind(blob_id)
+        .execute(&mut **tx)
+        .await
+        .map_err(db_error)?;
+
+    let row = sqlx::query(
+        "DELETE FROM blobs WHERE blob_id = $1
+         AND NOT EXISTS (SELECT 1 FROM files WHERE blob_id = $1)
+         RETURNING storage_key"
+    )
+    .bind(blob_id)
+    .fetch_optional(&mut **tx)
+    .await
+    .map_err(db_error)?;
+
+    Ok(row.map(|row| row.get("storage_key")))
+}
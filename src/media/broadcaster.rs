@@ -14,35 +14,49 @@ use webrtc::peer_connection::sdp::sdp_type::RTCSdpType;
 use crate::utils::error::{Error, Result};
 
 use std::time::Duration;
-use std::io::BufReader;
 use axum::body::Bytes;
 use tokio::sync::Mutex;
 use serde::Serialize;
 use webrtc::rtp::extension::audio_level_extension::AudioLevelExtension;
-use webrtc::media::{
-    Sample,
-    io::ogg_reader::OggReader,
-};
-use std::fs::File;
-use std::path::Path;
-use std::io::Write;
-use tokio::fs;
-use std::sync::atomic::{AtomicBool, Ordering};
+use webrtc::media::Sample;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::process::Stdio;
+use tokio::process::Command;
 use tokio::sync::mpsc;
 use std::collections::HashMap;
 use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
 
 use crate::models::peer::PeerConnection;
+use crate::models::queue::PlayQueue;
+use crate::media::file_manager::FFMPEG_PATH;
 
-use aws_config::meta::region::RegionProviderChain;
-use aws_sdk_s3::{config::Region, Client};
+use crate::storage::StorageBackend;
 
 const OGG_PAGE_DURATION: Duration = Duration::from_millis(20);
+const OPUS_SAMPLE_RATE: u64 = 48000;
+const PROGRESS_INTERVAL_MS: u64 = 5000;
+// bounded buffer between the ogg parser and the broadcast loop
+const STREAM_BUFFER_PAGES: usize = 50;
+// the last ogg page of a 128k opus file is always well within this range
+const OGG_TAIL_BYTES: u64 = 65536;
+// how long before the end of a track the next queue item gets prefetched
+const PREFETCH_WINDOW_MS: u64 = 15000;
+// crossfades have to fit inside the prefetch window, with time left to render them
+pub const MAX_CROSSFADE_MS: u64 = 10000;
+const OGG_PAGE_HEADER_SIZE: usize = 27;
+const OGG_BEGINNING_OF_STREAM: u8 = 0x02;
+const OGG_CRC_POLY: u32 = 0x04c1_1db7;
 
 #[derive(Debug)]
 pub enum BroadcasterCommand {
     Play { key: String },
     Pause,
+    Resume,
+    Seek { position_ms: u64 },
+    Prefetch { key: String },
+    DiscardPrefetch,
+    SetCrossfade { duration_ms: u64 },
     Stop,
     Attach {
         peer_id: String,
@@ -54,23 +68,124 @@ pub enum BroadcasterCommand {
 pub enum BroadcasterEvent {
     End,
     TrackAdded,
+    Seeked { position_ms: u64 },
+    NearEnd,
+    Progress { key: String, elapsed_ms: u64, duration_ms: u64 },
+}
+
+#[derive(Debug)]
+pub struct OggPage {
+    pub data: Bytes,
+    pub granule_position: u64,
+}
+
+// the mixed tail of one track and head of the next, rendered ahead of time by ffmpeg
+#[derive(Debug)]
+pub struct Transition {
+    pub from_key: String,
+    pub to_key: String,
+    pub start_ms: u64,
+    pub duration_ms: u64,
+    pub pages: mpsc::Receiver<OggPage>,
+}
+
+// splits the first complete ogg page off the buffer, None until all of its bytes arrived.
+// returns the header type along with the page, the payload is the page without its header
+fn split_page(buf: &mut Vec<u8>) -> Result<Option<(u8, OggPage)>> {
+    if buf.len() < OGG_PAGE_HEADER_SIZE {
+        return Ok(None);
+    }
+    if &buf[..4] != b"OggS" {
+        return Err(Error::BroadcasterError { msg: "Invalid ogg page signature".to_string() });
+    }
+
+    let header_len = OGG_PAGE_HEADER_SIZE + buf[26] as usize;
+    if buf.len() < header_len {
+        return Ok(None);
+    }
+    let payload_len: usize = buf[OGG_PAGE_HEADER_SIZE..header_len].iter().map(|s| *s as usize).sum();
+    if buf.len() < header_len + payload_len {
+        return Ok(None);
+    }
+
+    let page: Vec<u8> = buf.drain(..header_len + payload_len).collect();
+    let checksum = u32::from_le_bytes([page[22], page[23], page[24], page[25]]);
+    if ogg_checksum(&page) != checksum {
+        return Err(Error::BroadcasterError { msg: "Ogg page checksum mismatch".to_string() });
+    }
+
+    let mut granule = [0u8; 8];
+    granule.copy_from_slice(&page[6..14]);
+
+    Ok(Some((page[5], OggPage {
+        data: Bytes::copy_from_slice(&page[header_len..]),
+        granule_position: u64::from_le_bytes(granule),
+    })))
+}
+
+fn is_opus_head(header_type: u8, page: &OggPage) -> bool {
+    header_type == OGG_BEGINNING_OF_STREAM && page.data.starts_with(b"OpusHead")
+}
+
+// audio pages of a complete ogg opus file held in memory
+fn opus_pages(mut buf: Vec<u8>) -> Result<Vec<OggPage>> {
+    let mut pages = Vec::new();
+    match split_page(&mut buf)? {
+        Some((header_type, page)) if is_opus_head(header_type, &page) => {},
+        _ => return Err(Error::BroadcasterError { msg: "Not an ogg opus stream".to_string() }),
+    }
+    while let Some((_, page)) = split_page(&mut buf)? {
+        pages.push(page);
+    }
+    Ok(pages)
+}
+
+// crc32 of the whole page with the checksum field itself taken as zeros
+fn ogg_checksum(page: &[u8]) -> u32 {
+    page.iter().enumerate().fold(0u32, |sum, (i, byte)| {
+        let byte = if (22..26).contains(&i) { 0 } else { *byte };
+        let mut crc = sum ^ ((byte as u32) << 24);
+        for _ in 0..8 {
+            crc = if crc & 0x8000_0000 != 0 { (crc << 1) ^ OGG_CRC_POLY } else { crc << 1 };
+        }
+        crc
+    })
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct NowPlaying {
+    pub key: String,
+    pub elapsed_ms: u64,
+    pub duration_ms: u64,
 }
 
 #[derive(Clone, Debug)]
 pub struct BroadcasterHandle {
     pub cmd_tx: mpsc::Sender<BroadcasterCommand>,
-    pub event_rx: Arc<Mutex<mpsc::Receiver<BroadcasterEvent>>>
+    pub event_rx: Arc<Mutex<mpsc::Receiver<BroadcasterEvent>>>,
+    pub now_playing: Arc<Mutex<NowPlaying>>,
 }
 
 #[derive(Debug)]
 pub struct Broadcaster {
     audio_track: Arc<TrackLocalStaticSample>,
     is_broadcasting: Arc<AtomicBool>,
+    is_paused: Arc<AtomicBool>,
+    seek_target: Arc<Mutex<Option<u64>>>,
+    prefetched: Option<(String, mpsc::Receiver<OggPage>)>,
+    crossfade_ms: Arc<AtomicU64>,
+    transition: Arc<Mutex<Option<Transition>>>,
+    // bumped whenever the transition is replaced or cleared, so a render that finishes late is dropped
+    transition_generation: Arc<AtomicU64>,
+    resume_offset: Arc<Mutex<Option<(String, u64)>>>,
     cmd_rx: mpsc::Receiver<BroadcasterCommand>,
     event_tx: mpsc::Sender<BroadcasterEvent>,
     peer_connections: Arc<Mutex<HashMap<String, PeerConnection>>>,
-    s3_client: Client,
-    session_id: String,
+    now_playing: Arc<Mutex<NowPlaying>>,
+    storage: Arc<dyn StorageBackend>,
+    queue: Arc<Mutex<PlayQueue>>,
+    // task writing the pages of the current track, only one may write to the audio track
+    page_task: Option<JoinHandle<()>>,
 }
 
 impl Broadcaster {
@@ -80,24 +195,28 @@ impl Broadcaster {
         cmd_rx: mpsc::Receiver<BroadcasterCommand>,
         event_tx: mpsc::Sender<BroadcasterEvent>,
         peer_connections: Arc<Mutex<HashMap<String, PeerConnection>>>,
-        session_id: String,
-
+        now_playing: Arc<Mutex<NowPlaying>>,
+        storage: Arc<dyn StorageBackend>,
+        queue: Arc<Mutex<PlayQueue>>,
     ) -> Result<Self> {
 
-        // s3 client for downloading single file only
-        let region_provider = RegionProviderChain::first_try(Region::new("us-east-1"));
-        let region = region_provider.region().await.unwrap();
-        let shared_config = aws_config::from_env().region(region_provider).load().await;
-        let client = Client::new(&shared_config);
-
         Ok(Self {
             audio_track: track,
             is_broadcasting: Arc::new(AtomicBool::new(false)),
+            is_paused: Arc::new(AtomicBool::new(false)),
+            seek_target: Arc::new(Mutex::new(None)),
+            prefetched: None,
+            crossfade_ms: Arc::new(AtomicU64::new(0)),
+            transition: Arc::new(Mutex::new(None)),
+            transition_generation: Arc::new(AtomicU64::new(0)),
+            resume_offset: Arc::new(Mutex::new(None)),
             cmd_rx,
             event_tx,
             peer_connections,
-            s3_client: client,
-            session_id,
+            now_playing,
+            storage,
+            queue,
+            page_task: None,
         })
     }
 
@@ -108,16 +227,43 @@ impl Broadcaster {
                     if key.is_empty() {
                         continue;
                     }
-                    match self.set_active_file(key.clone()).await {
-                        Ok(file_path) => {
-                            self.broadcast(&file_path).await?;
-                        },
+                    match self.broadcast(&key).await {
+                        Ok(_) => {},
                         Err(e) => {
-                            println!("Error setting active file: {:?}", e);
+                            println!("Error opening stream: {:?}", e);
                         }
                     }
                 }
                 BroadcasterCommand::Pause => {
+                    self.pause().await;
+                }
+                BroadcasterCommand::Resume => {
+                    self.resume().await;
+                }
+                BroadcasterCommand::Seek { position_ms } => {
+                    self.seek(position_ms).await;
+                }
+                BroadcasterCommand::Prefetch { key } => {
+                    if key.is_empty() {
+                        continue;
+                    }
+                    match Broadcaster::open_stream(&self.storage, &key).await {
+                        Ok(pages) => {
+                            self.prefetched = Some((key.clone(), pages));
+                        },
+                        Err(e) => {
+                            println!("Error prefetching stream: {:?}", e);
+                        }
+                    }
+                    if let Err(e) = self.prepare_transition(&key).await {
+                        println!("Error preparing crossfade: {:?}", e);
+                    }
+                }
+                BroadcasterCommand::DiscardPrefetch => {
+                    self.discard_prefetch().await;
+                }
+                BroadcasterCommand::SetCrossfade { duration_ms } => {
+                    self.crossfade_ms.store(duration_ms.min(MAX_CROSSFADE_MS), Ordering::Release);
                 }
                 BroadcasterCommand::Stop => {
                     self.stop().await;
@@ -148,120 +294,499 @@ impl Broadcaster {
         Ok(())
     }
     
-    pub async fn set_active_file(&self, key: String) -> Result<(String)> {
+    // opens the object as a bounded stream of ogg pages, playback can start as soon
+    // as the first pages arrive instead of waiting for the whole download
+    pub async fn open_stream(storage: &Arc<dyn StorageBackend>, key: &str) -> Result<mpsc::Receiver<OggPage>> {
+        let chunk_rx = storage.get_stream(key).await?;
+        Ok(Broadcaster::spawn_page_parser(chunk_rx))
+    }
+
+    // pages are split straight off the byte stream (storage read or ffmpeg stdout) on a regular
+    // task, so an open stream does not hold a blocking thread for the length of the track
+    fn spawn_page_parser(mut chunk_rx: mpsc::Receiver<Bytes>) -> mpsc::Receiver<OggPage> {
+
+        let (page_tx, page_rx) = mpsc::channel::<OggPage>(STREAM_BUFFER_PAGES);
+
+        tokio::spawn(async move {
+            let mut buf = Vec::new();
+            let mut header_seen = false;
+
+            loop {
+                let (header_type, page) = match split_page(&mut buf) {
+                    Ok(Some(page)) => page,
+                    Ok(None) => match chunk_rx.recv().await {
+                        Some(chunk) => {
+                            buf.extend_from_slice(&chunk);
+                            continue;
+                        },
+                        None => break,
+                    },
+                    Err(e) => {
+                        println!("Error parsing ogg page: {:?}", e);
+                        break;
+                    }
+                };
+
+                // the first page is the opus id header, it carries no audio
+                if !header_seen {
+                    if !is_opus_head(header_type, &page) {
+                        println!("Error parsing ogg header: not an opus stream");
+                        break;
+                    }
+                    header_seen = true;
+                    continue;
+                }
+
+                if page_tx.send(page).await.is_err() {
+                    break;
+                }
+            }
+        });
 
-        // first check if file exists
-        let session_id = self.session_id.clone();
-        let session_dir = format!("./sessions/{}", session_id.clone());
+        page_rx
+    }
 
-        // Ensure the output directory exists
-        if !Path::new(&session_dir).exists() {
-            tokio::fs::create_dir_all(session_dir.clone()).await?;
+    // pulls pages until the granule position reaches the target, returns the last granule seen
+    async fn skip_to(pages: &mut mpsc::Receiver<OggPage>, position_ms: u64) -> u64 {
+        let target_granule = position_ms.saturating_mul(OPUS_SAMPLE_RATE) / 1000;
+        let mut last_granule = 0;
+        while let Some(page) = pages.recv().await {
+            last_granule = page.granule_position;
+            if last_granule >= target_granule {
+                break;
+            }
         }
+        last_granule
+    }
 
-        // if there are any files left in session_dir, delete them
-        let mut entries = match fs::read_dir(session_dir.clone()).await {
-            Ok(entries) => entries,
-            Err(e) => return Err(Error::ResetFileError { msg: e.to_string() }),
+    // mixes the last crossfade_ms of the current track with the first crossfade_ms of next_key
+    // and re-encodes it to the same 128k opus in ogg format the files are stored in
+    pub async fn prepare_transition(&self, next_key: &str) -> Result<()> {
+
+        let crossfade_ms = self.crossfade_ms.load(Ordering::Acquire);
+        let (current_key, duration_ms) = {
+            let now_playing = self.now_playing.lock().await;
+            (now_playing.key.clone(), now_playing.duration_ms)
         };
 
-        while let Some(entry) = entries.next_entry().await.transpose() {
-            let entry = entry?;
-            let path = entry.path();
-            if path.is_file() {
-                fs::remove_file(path).await?;
-            }
+        if crossfade_ms == 0 || current_key.is_empty() || duration_ms <= crossfade_ms {
+            return Ok(());
         }
-        
-        let file_dir = format!("{}/{}.ogg", session_dir, key);
-
-        let mut file = File::create(file_dir).map_err(|err| {
-            Error::S3DownloadError { msg: "Failed to initialize file for s3 download".to_string() }
-        })?;
-
-        let mut object = self.s3_client
-            .get_object()
-            .bucket("antaresmusicshare")
-            .key(format!("{}.ogg", key))
-            .send()
-            .await
-            .map_err(
-                |e| {
-                    Error::S3DownloadError { msg: e.to_string() }
+
+        let start_ms = duration_ms - crossfade_ms;
+        let current_url = self.storage.input_url(&current_key).await?;
+        let next_url = self.storage.input_url(next_key).await?;
+        let seconds = format!("{:.3}", crossfade_ms as f64 / 1000.0);
+
+        let generation = self.transition_generation.fetch_add(1, Ordering::AcqRel) + 1;
+
+        let mut command = Command::new(FFMPEG_PATH);
+        command
+            .args(["-loglevel", "error"])
+            .args(["-ss", &format!("{:.3}", start_ms as f64 / 1000.0)])
+            .args(["-i", &current_url])
+            .args(["-i", &next_url])
+            .args([
+                "-filter_complex",
+                &format!(
+                    "[0:a]atrim=duration={0}[a0];[1:a]atrim=duration={0}[a1];[a0][a1]acrossfade=d={0}",
+                    seconds
+                ),
+            ])
+            .args(["-c:a", "libopus", "-b:a", "128k", "-page_duration", "20000", "-f", "ogg"])
+            .arg("pipe:1")
+            .kill_on_drop(true);
+
+        let transition = self.transition.clone();
+        let transition_generation = self.transition_generation.clone();
+        let next_key = next_key.to_string();
+
+        // the render is only used once ffmpeg finished cleanly, anything else leaves a plain cut
+        tokio::spawn(async move {
+            let output = match command.output().await {
+                Ok(output) => output,
+                Err(e) => {
+                    println!("Error running ffmpeg for crossfade: {:?}", e);
+                    return;
                 }
-            )?;
-        
-        // download the file to the session directory with file manager
-        let mut byte_count = 0_usize;
-        while let Some(bytes) = object.body.try_next().await.map_err(|err| {
-            Error::S3DownloadError { msg: "Failed to read from s3 download streams".to_string() }
-        })? {
-            let bytes_len = bytes.len();
-            file.write_all(&bytes).map_err(|err| {
-                Error::S3DownloadError { msg: "Failed to write from s3 stream to local file".to_string() }
-            })?;
-            byte_count += bytes_len;
-        }
+            };
+            if !output.status.success() {
+                println!("ffmpeg failed to render crossfade: {}", String::from_utf8_lossy(&output.stderr));
+                return;
+            }
 
-        if byte_count == 0 {
-            return Err(Error::S3DownloadError { msg: "Failed to download file".to_string() });
-        }
+            let pages = match opus_pages(output.stdout) {
+                Ok(pages) if !pages.is_empty() => pages,
+                Ok(_) => {
+                    println!("ffmpeg rendered an empty crossfade");
+                    return;
+                },
+                Err(e) => {
+                    println!("Error parsing crossfade: {:?}", e);
+                    return;
+                }
+            };
+
+            // only as much of the next track as actually got mixed in is skipped later
+            let rendered_ms = pages.last().map_or(0, |page| page.granule_position * 1000 / OPUS_SAMPLE_RATE);
+            let (page_tx, page_rx) = mpsc::channel::<OggPage>(pages.len());
+            for page in pages {
+                let _ = page_tx.try_send(page);
+            }
+
+            let mut transition = transition.lock().await;
+            if transition_generation.load(Ordering::Acquire) == generation {
+                *transition = Some(Transition {
+                    from_key: current_key,
+                    to_key: next_key,
+                    start_ms,
+                    duration_ms: crossfade_ms.min(rendered_ms),
+                    pages: page_rx,
+                });
+            }
+        });
+
+        Ok(())
+    }
+
+    // drops the transition along with any render still running for it
+    async fn clear_transition(&self) -> Option<Transition> {
+        let mut transition = self.transition.lock().await;
+        self.transition_generation.fetch_add(1, Ordering::AcqRel);
+        transition.take()
+    }
+
+    // reads the tail of the object and takes the granule position of the last page,
+    // which is the total number of samples in the track
+    pub async fn get_duration(storage: &Arc<dyn StorageBackend>, key: &str) -> Result<u64> {
+
+        let tail = storage.get_tail(key, OGG_TAIL_BYTES).await?;
+
+        let last_page = tail
+            .windows(4)
+            .rposition(|w| w == b"OggS")
+            .filter(|pos| pos + 14 <= tail.len())
+            .ok_or(Error::StorageFailed { msg: "No ogg page found in file".to_string() })?;
 
-        let file_path = format!("{}/{}.ogg", session_dir, key);
+        let mut granule = [0u8; 8];
+        granule.copy_from_slice(&tail[last_page + 6..last_page + 14]);
 
-        Ok(file_path.to_string())
+        Ok(u64::from_le_bytes(granule) * 1000 / OPUS_SAMPLE_RATE)
     }
 
-    pub async fn broadcast(&self, 
-        file_path: &str,
+    pub async fn broadcast(&mut self, 
+        key: &str,
     ) -> Result<()> {
 
+        // reuse the prefetched stream if it is for this key, otherwise it is stale
+        let mut pages = match self.prefetched.take() {
+            Some((prefetched_key, pages)) if prefetched_key == key => pages,
+            _ => Broadcaster::open_stream(&self.storage, key).await?,
+        };
+
+        // the previous track has to be gone before this one starts, otherwise both
+        // write pages to the audio track and the old one still sends its End
+        self.end_page_task().await;
+
         // upon function call, set the is_broadcasting flag to true
+        // a new track always starts unpaused
         self.is_broadcasting.store(true, Ordering::Release);
+        self.is_paused.store(false, Ordering::Release);
+        *self.seek_target.lock().await = None;
+        self.clear_transition().await;
+
+        // the head of this track was already played as part of a crossfade
+        let start_ms = match self.resume_offset.lock().await.take() {
+            Some((resume_key, offset_ms)) if resume_key == key => offset_ms,
+            _ => 0,
+        };
 
-        let file_name = file_path.to_owned();
+        *self.now_playing.lock().await = NowPlaying {
+            key: key.to_string(),
+            elapsed_ms: start_ms,
+            duration_ms: 0,
+        };
+
+        let key = key.to_owned();
+        let storage = self.storage.clone();
         let audio_track = self.audio_track.clone();
 
         let is_broadcasting = self.is_broadcasting.clone();
+        let is_paused = self.is_paused.clone();
+        let seek_target = self.seek_target.clone();
+        let transition = self.transition.clone();
+        let queue = self.queue.clone();
+        let resume_offset = self.resume_offset.clone();
+        let now_playing = self.now_playing.clone();
         let event_tx = self.event_tx.clone();
 
-        tokio::spawn(async move {
-            let file = File::open(file_name).unwrap();
-            
-            let reader = BufReader::new(file);
-            let (mut ogg, _) = OggReader::new(reader, true).unwrap();
-            
+        // the duration needs a separate ranged request, fill it in once it arrives
+        {
+            let storage = storage.clone();
+            let now_playing = now_playing.clone();
+            let key = key.clone();
+            tokio::spawn(async move {
+                match Broadcaster::get_duration(&storage, &key).await {
+                    Ok(duration_ms) => {
+                        let mut now_playing = now_playing.lock().await;
+                        if now_playing.key == key {
+                            now_playing.duration_ms = duration_ms;
+                        }
+                    },
+                    Err(e) => {
+                        println!("Error getting duration: {:?}", e);
+                    }
+                }
+            });
+        }
+
+        self.page_task = Some(tokio::spawn(async move {
             let mut ticker = tokio::time::interval(OGG_PAGE_DURATION);
 
             let mut last_granule: u64 = 0;
-            while let Ok((page_data, page_header)) = ogg.parse_next_page() {
+            if start_ms > 0 {
+                last_granule = Broadcaster::skip_to(&mut pages, start_ms).await;
+            }
+
+            // offset of the page stream, non zero while playing a crossfade
+            let mut base_ms: u64 = 0;
+            let mut crossfading_into: Option<(String, u64)> = None;
+
+            let mut last_progress_ms: u64 = last_granule * 1000 / OPUS_SAMPLE_RATE;
+            let mut near_end_sent = false;
+            loop {
 
                 if is_broadcasting.load(Ordering::Acquire) == false {
                     // must return here so it doesnt send the End event
                     return;
                 }
 
-                let sample_count = page_header.granule_position - last_granule;
-                last_granule = page_header.granule_position;
-                let sample_duration = Duration::from_millis((sample_count * 1000) / 48000);
+                // ogg pages can only be walked forward, so a seek reopens the stream
+                // and skips pages until the granule position reaches the target
+                // the crossfade is already mixed, so seeking is ignored until the next track starts
+                let pending_seek = seek_target.lock().await.take();
+                if let (Some(position_ms), None) = (pending_seek, &crossfading_into) {
+                    pages = match Broadcaster::open_stream(&storage, &key).await {
+                        Ok(pages) => pages,
+                        Err(_) => break,
+                    };
+                    last_granule = Broadcaster::skip_to(&mut pages, position_ms).await;
+
+                    let position_ms = last_granule * 1000 / OPUS_SAMPLE_RATE;
+                    now_playing.lock().await.elapsed_ms = position_ms;
+                    last_progress_ms = position_ms;
+
+                    let _ = event_tx.send(BroadcasterEvent::Seeked { position_ms }).await;
+                }
+
+                // hold the reader at the current page while paused, so playback
+                // continues from the same granule position on resume
+                if is_paused.load(Ordering::Acquire) {
+                    let _ = ticker.tick().await;
+                    continue;
+                }
+
+                // switch over to the rendered crossfade once the current track reaches its start
+                if crossfading_into.is_none() {
+                    let elapsed_ms = last_granule * 1000 / OPUS_SAMPLE_RATE;
+                    let ready = {
+                        let mut transition = transition.lock().await;
+                        let ready = transition
+                            .as_ref()
+                            .is_some_and(|t| t.from_key == key && elapsed_ms >= t.start_ms);
+                        if ready { transition.take() } else { None }
+                    };
+                    // the queue may have changed since the render, only fade into the item that is still next
+                    if let Some(t) = ready {
+                        if queue.lock().await.peek_next() == t.to_key {
+                            pages = t.pages;
+                            base_ms = t.start_ms;
+                            last_granule = 0;
+                            crossfading_into = Some((t.to_key, t.duration_ms));
+                        }
+                    }
+                }
+
+                let page = match pages.recv().await {
+                    Some(page) => page,
+                    None => break,
+                };
+
+                let sample_count = page.granule_position - last_granule;
+                last_granule = page.granule_position;
+                let sample_duration = Duration::from_millis((sample_count * 1000) / OPUS_SAMPLE_RATE);
                 // println!("Sample duration: {:?}", sample_duration);
 
                 audio_track
                     .write_sample(&Sample {
-                        data: page_data.freeze(),
+                        data: page.data,
                         duration: sample_duration,
                         ..Default::default()
                     }).await;
 
+                let elapsed_ms = base_ms + last_granule * 1000 / OPUS_SAMPLE_RATE;
+                let duration_ms = {
+                    let mut now_playing = now_playing.lock().await;
+                    now_playing.elapsed_ms = elapsed_ms;
+                    now_playing.duration_ms
+                };
+
+                // ask for the next queue item while there is still audio left to cover the request
+                if !near_end_sent && duration_ms > 0 && elapsed_ms + PREFETCH_WINDOW_MS >= duration_ms {
+                    near_end_sent = true;
+                    let _ = event_tx.send(BroadcasterEvent::NearEnd).await;
+                }
+
+                // periodic progress so listeners that joined mid-song stay in sync
+                if elapsed_ms >= last_progress_ms + PROGRESS_INTERVAL_MS {
+                    last_progress_ms = elapsed_ms;
+                    let _ = event_tx.send(BroadcasterEvent::Progress {
+                        key: key.clone(),
+                        elapsed_ms,
+                        duration_ms,
+                    }).await;
+                }
+
                 let _ = ticker.tick().await;
             }
 
+            // the next track picks up after the part that was mixed into the crossfade
+            if let Some(next) = crossfading_into {
+                *resume_offset.lock().await = Some(next);
+            }
+
+            *now_playing.lock().await = NowPlaying::default();
             event_tx.send(BroadcasterEvent::End).await;
-        });
+        }));
         Ok(())
     }
 
-    pub async fn stop(&self) {
+    // aborts the page task and waits for it, so it can not write or send events afterwards
+    async fn end_page_task(&mut self) {
+        if let Some(task) = self.page_task.take() {
+            task.abort();
+            let _ = task.await;
+        }
+    }
+
+    pub async fn stop(&mut self) {
+        self.end_page_task().await;
         self.is_broadcasting.store(false, Ordering::Release);
+        self.is_paused.store(false, Ordering::Release);
+        *self.now_playing.lock().await = NowPlaying::default();
+    }
+
+    pub async fn discard_prefetch(&mut self) {
+        let transition = self.clear_transition().await;
+        if self.prefetched.take().is_none() && transition.is_none() {
+            return;
+        }
+
+        // the queue changed inside the prefetch window, ask again for the new next item
+        let now_playing = self.now_playing.lock().await;
+        if self.is_broadcasting.load(Ordering::Acquire)
+            && now_playing.duration_ms > 0
+            && now_playing.elapsed_ms + PREFETCH_WINDOW_MS >= now_playing.duration_ms
+        {
+            let _ = self.event_tx.send(BroadcasterEvent::NearEnd).await;
+        }
+    }
+
+    pub async fn pause(&self) {
+        self.is_paused.store(true, Ordering::Release);
+    }
+
+    pub async fn resume(&self) {
+        self.is_paused.store(false, Ordering::Release);
+    }
+
+    pub async fn seek(&self, position_ms: u64) {
+        // picked up by the running broadcast task before its next page
+        if self.is_broadcasting.load(Ordering::Acquire) {
+            // a target past the end just plays out the last page
+            let duration_ms = self.now_playing.lock().await.duration_ms;
+            let position_ms = if duration_ms > 0 { position_ms.min(duration_ms) } else { position_ms };
+            *self.seek_target.lock().await = Some(position_ms);
+        }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn page(header_type: u8, granule_position: u64, payload: &[u8]) -> Vec<u8> {
+        let mut page = b"OggS".to_vec();
+        page.extend_from_slice(&[0, header_type]);
+        page.extend_from_slice(&granule_position.to_le_bytes());
+        page.extend_from_slice(&[1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        page.push(1);
+        page.push(payload.len() as u8);
+        page.extend_from_slice(payload);
+
+        let checksum = ogg_checksum(&page);
+        page[22..26].copy_from_slice(&checksum.to_le_bytes());
+        page
+    }
+
+    #[test]
+    fn split_page_waits_for_whole_pages() {
+        let mut stream = page(OGG_BEGINNING_OF_STREAM, 0, b"OpusHead");
+        stream.extend(page(0, 960, b"audio"));
+
+        let mut buf = stream[..30].to_vec();
+        assert!(split_page(&mut buf).unwrap().is_none());
+
+        buf.extend_from_slice(&stream[30..]);
+        let (header_type, head) = split_page(&mut buf).unwrap().unwrap();
+        assert_eq!(header_type, OGG_BEGINNING_OF_STREAM);
+        assert_eq!(&head.data[..], b"OpusHead");
+
+        let (_, audio) = split_page(&mut buf).unwrap().unwrap();
+        assert_eq!(&audio.data[..], b"audio");
+        assert_eq!(audio.granule_position, 960);
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn split_page_rejects_corrupt_pages() {
+        let mut buf = page(0, 960, b"audio");
+        let last = buf.len() - 1;
+        buf[last] ^= 1;
+        assert!(split_page(&mut buf).is_err());
+
+        let mut buf = b"not an ogg page at all, just bytes".to_vec();
+        assert!(split_page(&mut buf).is_err());
+    }
+
+    #[test]
+    fn opus_pages_requires_the_id_header() {
+        let mut render = page(OGG_BEGINNING_OF_STREAM, 0, b"OpusHead");
+        render.extend(page(0, 960, b"audio"));
+        let pages = opus_pages(render).unwrap();
+        assert_eq!(pages.len(), 1);
+        assert_eq!(pages[0].granule_position, 960);
+
+        assert!(opus_pages(Vec::new()).is_err());
+        assert!(opus_pages(page(0, 960, b"audio")).is_err());
+    }
+
+    #[tokio::test]
+    async fn parser_skips_the_id_header() {
+        let (chunk_tx, chunk_rx) = mpsc::channel(4);
+        let mut pages = Broadcaster::spawn_page_parser(chunk_rx);
+
+        let mut stream = page(OGG_BEGINNING_OF_STREAM, 0, b"OpusHead");
+        stream.extend(page(0, 0, b"OpusTags"));
+        stream.extend(page(0, 960, b"audio"));
+        // chunk boundaries do not line up with pages
+        for chunk in stream.chunks(7) {
+            chunk_tx.send(Bytes::copy_from_slice(chunk)).await.unwrap();
+        }
+        drop(chunk_tx);
+
+        assert_eq!(&pages.recv().await.unwrap().data[..], b"OpusTags");
+        assert_eq!(pages.recv().await.unwrap().granule_position, 960);
+        assert!(pages.recv().await.is_none());
+    }
+}
@@ -5,44 +5,151 @@ use std::io::Write;
 use std::process::Stdio;
 use std::sync::Arc;
 use serde_json::Value;
+use serde::{Deserialize, Serialize};
 use tokio::process::Command;
-use tokio::sync::Semaphore;
+use tokio::io::{ AsyncBufReadExt, BufReader };
+use tokio::sync::{ Notify, Semaphore };
 use tokio::task;
 use futures::stream::{FuturesUnordered, StreamExt};
 use sqlx::PgPool;
 use sqlx::Row;
+use sqlx::postgres::PgRow;
 use dotenvy::dotenv;
 use std::env;
 
-use aws_config::meta::region::RegionProviderChain;
-use aws_sdk_s3::{config::Region, Client};
+use crate::storage::StorageBackend;
+use crate::utils::retry::RetryPolicies;
+use crate::media::quota::Quota;
+use crate::media::blobs::{ self, NewBlob };
 
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
 use tokio::sync::Mutex;
 use tokio::sync::broadcast;
 use std::collections::HashMap;
 
 const COOKIES_PATH: &str = "./libs/cookies.txt";
 const YT_DLP_PATH: &str = "./libs/yt-dlp";
-const FFMPEG_PATH: &str = "./libs/ffmpeg";
+pub const FFMPEG_PATH: &str = "./libs/ffmpeg";
+const CONVERTED_DIR: &str = "./converted";
+// containers accepted by the upload route, everything is transcoded to opus in ogg
+pub const UPLOAD_FORMATS: [&str; 4] = ["mp3", "flac", "wav", "ogg"];
+
+// ebu r128 measurement of a stored file, ffmpeg prints every value as a string
+#[derive(Clone, Debug, Deserialize)]
+pub struct LoudnessStats {
+    pub input_i: String,
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct TrackMetadata {
+    pub duration_ms: Option<i32>,
+    pub artist: Option<String>,
+    pub thumbnail: Option<String>,
+    pub original_format: Option<String>,
+}
+
+impl TrackMetadata {
+    // picks the fields we keep out of yt-dlp's info json
+    pub fn from_info(info: &Value) -> Self {
+        Self {
+            duration_ms: info["duration"].as_f64().map(|d| (d * 1000.0).round() as i32),
+            artist: info["artist"].as_str()
+                .or(info["uploader"].as_str())
+                .or(info["channel"].as_str())
+                .map(String::from),
+            thumbnail: info["thumbnail"].as_str().map(String::from),
+            original_format: info["ext"].as_str().map(String::from),
+        }
+    }
 
+    pub fn from_row(row: &PgRow) -> Self {
+        Self {
+            duration_ms: row.get("duration_ms"),
+            artist: row.get("artist"),
+            thumbnail: row.get("thumbnail"),
+            original_format: row.get("original_format"),
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct FMDownloadParams{
     pub url: String,
     pub title: String,
     pub userid: String,
     pub pool: PgPool,
+    // set for downloads that run as a job, uploads have none
+    pub job_id: Option<i32>,
+}
+
+// what download_notify sends, one json object per stage of a download
+#[derive(Clone, Debug, Serialize)]
+pub struct DownloadEvent {
+    pub job_id: Option<i32>,
+    pub url: String,
+    pub title: String,
+    #[serde(flatten)]
+    pub stage: DownloadStage,
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum DownloadStage {
+    Queued,
+    Downloading {
+        percent: f64,
+        downloaded_bytes: u64,
+        total_bytes: Option<u64>,
+    },
+    Converting,
+    Uploading,
+    Done { uuid: String },
+    Failed { code: String, reason: String },
+    Cancelled,
+}
+
+// yt-dlp prints one of these per progress update, see --progress-template below
+const PROGRESS_PREFIX: &str = "[progress]";
+const PROGRESS_TEMPLATE: &str = "download:[progress] %(progress.status)s %(progress.downloaded_bytes)s %(progress.total_bytes)s %(progress.total_bytes_estimate)s";
+
+/// A file written during a request that is removed again when dropped.
+#[derive(Debug)]
+pub struct TempFile {
+    path: Option<String>,
+}
+
+impl TempFile {
+    pub fn new(path: String) -> Self {
+        Self { path: Some(path) }
+    }
+
+    pub fn path(&self) -> &str {
+        self.path.as_deref().unwrap_or("")
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        if let Some(path) = self.path.take() {
+            let _ = std::fs::remove_file(path);
+        }
+    }
 }
 
 #[derive(Clone, Debug)]
 pub struct FileManager {
     pub semaphore: Arc<Semaphore>,
     pub max_file_size: u64,
-    pub s3_client: Client,
+    pub storage: Arc<dyn StorageBackend>,
     pub processing_user: Arc<Mutex<HashMap<String, broadcast::Sender<String>>>>,
+    pub loudness_target: f64,
+    pub normalize_loudness: bool,
+    pub retry: RetryPolicies,
+    pub quota: Quota,
 }
 
 impl FileManager {
-    pub async fn new() -> Result<(Self)> {
+    pub async fn new(storage: Arc<dyn StorageBackend>) -> Result<(Self)> {
 
         dotenv().ok();
         let max_concurrent_downloads = env::var("MAX_CONCURRENT_TASKS")
@@ -52,19 +159,24 @@ impl FileManager {
 
         let semaphore = Arc::new(Semaphore::new(max_concurrent_downloads));
 
-        let region_provider = RegionProviderChain::first_try(Region::new("us-east-1"));
-        let region = region_provider.region().await.unwrap();
-        let shared_config = aws_config::from_env().region(region_provider).load().await;
-        let client = Client::new(&shared_config);
-
         Ok(Self {
             semaphore,
             max_file_size: env::var("MAX_FILE_SIZE")
                 .unwrap_or("10000000".to_string())
                 .parse::<u64>()
                 .expect("MAX_FILE_SIZE must be a number"),
-            s3_client: client,
+            storage,
             processing_user: Arc::new(Mutex::new(HashMap::new())),
+            loudness_target: env::var("LOUDNESS_TARGET")
+                .unwrap_or("-16".to_string())
+                .parse::<f64>()
+                .expect("LOUDNESS_TARGET must be a number"),
+            normalize_loudness: env::var("NORMALIZE_LOUDNESS")
+                .unwrap_or("true".to_string())
+                .parse::<bool>()
+                .expect("NORMALIZE_LOUDNESS must be true or false"),
+            retry: RetryPolicies::from_env(),
+            quota: Quota::from_env(),
         })
     }
 
@@ -126,7 +238,10 @@ impl FileManager {
         }
     }
 
-    pub async fn process_audio(&self, params: FMDownloadParams) -> Result<()> {
+    // returns the uuid the converted file is stored under, notifying cancel stops the
+    // download or conversion and removes whatever it left in ./converted. once the file
+    // is being stored it runs to completion, the caller rolls back a late cancel
+    pub async fn process_audio(&self, params: FMDownloadParams, cancel: Arc<Notify>) -> Result<String> {
 
         let url = params.url.clone();
         let user_id = params.userid.clone().parse::<i32>().unwrap();
@@ -140,8 +255,6 @@ impl FileManager {
         .await {
             Ok(files) => {
                 if files.len() > 0 {
-                    let sender = self.get_sender_with_id(params.userid.clone()).await?;
-                    sender.send(params.title.clone()).unwrap_or(0);
                     return Err(Error::DuplicateContent { msg: url.to_string() });
                 }
             }
@@ -150,39 +263,123 @@ impl FileManager {
             }
         }
 
+        // refuse before downloading anything, the final size is checked again when storing
+        self.quota.usage(&params.pool, user_id).await?.check(0)?;
+
+        // someone already downloaded this source, the user only gets a reference to it
+        if let Some(source_url) = blobs::normalize_source_url(&url) {
+            if let Some(blob) = blobs::find_by_source(&params.pool, &source_url).await? {
+                blobs::link(&params.pool, &self.quota, &params, &blob).await?;
+                println!("task done (shared): {}", url);
+                self.notify(&params, DownloadStage::Done { uuid: blob.storage_key.clone() }).await;
+                return Ok(blob.storage_key);
+            }
+        }
+
+        let uuid = uuid::Uuid::new_v4().to_string();
         let sem_clone = self.semaphore.clone();
-        let _permit = sem_clone.acquire().await.unwrap();
 
-        self._process_audio(params).await?;
+        // dropping this future kills the running child process and releases the permit
+        let work = async {
+            let permit = sem_clone.acquire().await.unwrap();
+            self._process_audio(&params, &uuid).await.map(|metadata| (permit, metadata))
+        };
+
+        let (_permit, metadata) = tokio::select! {
+            result = work => result?,
+            _ = cancel.notified() => {
+                println!("processing-cancelled: {}", url);
+                FileManager::remove_partial_files(&uuid).await;
+                return Err(Error::JobCancelled);
+            }
+        };
+
+        // not raced against cancel, dropping it between the upload and the blob rows
+        // would leave an object nothing references or a blob nobody was charged for
+        let key = self.store_converted(&params, &uuid, metadata).await?;
 
-        Ok(())
+        println!("task done: {}", url);
+        self.notify(&params, DownloadStage::Done { uuid: key.clone() }).await;
+        Ok(key)
+    }
+
+    // keeps the highest attempt any stage of a job needed, nothing to record for uploads
+    async fn record_attempt(&self, params: &FMDownloadParams, attempt: u32) {
+        let Some(job_id) = params.job_id else {
+            return;
+        };
+
+        if let Err(e) = sqlx::query("UPDATE download_jobs SET attempts = GREATEST(attempts, $2) WHERE job_id = $1")
+            .bind(job_id)
+            .bind(attempt as i32)
+            .execute(&params.pool)
+            .await
+        {
+            eprintln!("could not record attempt of job {}: {:?}", job_id, e);
+        }
     }
 
-    pub async fn _process_audio(&self, params: FMDownloadParams) -> Result<()> {
+    // everything yt-dlp and ffmpeg write for a download is named after its uuid
+    pub async fn remove_partial_files(uuid: &str) {
+        let Ok(mut entries) = tokio::fs::read_dir(CONVERTED_DIR).await else {
+            return;
+        };
+
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            if entry.file_name().to_string_lossy().starts_with(uuid) {
+                tokio::fs::remove_file(entry.path()).await;
+            }
+        }
+    }
+
+    // probe, download and convert into ./converted/<uuid>.ogg
+    pub async fn _process_audio(&self, params: &FMDownloadParams, uuid: &str) -> Result<TrackMetadata> {
 
         let url = params.url.clone();
         let title = params.title.clone();
         let userid: i32 = params.userid.clone().parse::<i32>().unwrap();
         let pool = params.pool.clone();
-        let uuid = uuid::Uuid::new_v4().to_string();
 
-        match FileManager::get_file_size(url.clone()).await {
-            Ok(_) => {}
-            Err(e) => {
-                return Err(e);
-            }
-        }
+        self.retry.probe.run(
+            "probe",
+            || FileManager::get_file_size(url.clone()),
+            |attempt| self.record_attempt(params, attempt),
+        ).await?;
 
         println!("processing-start: {}", url);
         // Ensure the output directory exists
-        let converted_dir = "./converted";
+        let converted_dir = CONVERTED_DIR;
         if !Path::new(converted_dir).exists() {
             tokio::fs::create_dir_all(converted_dir).await?;
         }
 
+        // a failed attempt may leave fragments behind, every attempt starts clean
+        let metadata = self.retry.download.run(
+            "download",
+            || async move {
+                FileManager::remove_partial_files(uuid).await;
+                self.download(params, uuid).await
+            },
+            |attempt| self.record_attempt(params, attempt),
+        ).await?;
+
+        let change_file_name = Command::new("mv")
+            .arg(format!("{}/{}.ogg.opus", converted_dir, uuid))
+            .arg(format!("{}/{}.ogg", converted_dir, uuid))
+            .output()
+            .await?;
+
+        Ok(metadata)
+    }
+
+    // runs yt-dlp into ./converted/<uuid>.ogg.opus, reporting progress along the way
+    async fn download(&self, params: &FMDownloadParams, uuid: &str) -> Result<TrackMetadata> {
+
+        let url = params.url.clone();
+        let converted_dir = CONVERTED_DIR;
         let output_path = format!("{}/{}.ogg", converted_dir, uuid);
 
-        let output = Command::new(YT_DLP_PATH)
+        let mut child = Command::new(YT_DLP_PATH)
             // Supply cookies
             .args(&["--cookies", COOKIES_PATH])
             // Point yt-dlp to custom FFmpeg
@@ -202,82 +399,403 @@ impl FileManager {
             // Pass FFmpeg options to encode Opus in Ogg at 128 kbps with page duration
             .args(&[
                 "--postprocessor-args",
-                "ffmpeg:-c:a libopus -b:a 128k -page_duration 20000 -f ogg"
+                &format!("ffmpeg:{}", self.encode_args().join(" ")),
             ])
             // Explicitly name the final file .ogg
             .args(&["-o", &output_path])
+            // Print the info json of the downloaded video to stdout, still downloading it
+            .args(["--dump-json", "--no-simulate"])
+            // --dump-json implies quiet, keep the progress lines one per update
+            .args(["--progress", "--newline", "--progress-template", PROGRESS_TEMPLATE])
             // Finally, the video URL
             .arg(url.clone())
-            // Consider removing Stdio::null() if you want to see any errors or logs
-            .stdout(Stdio::null())
-            .stderr(Stdio::null())
-            .output()
-            .await?;
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()?;
+
+        let stdout = child.stdout.take().ok_or(Error::DownloadFailed { url: url.to_string() })?;
+        let stderr = child.stderr.take().ok_or(Error::DownloadFailed { url: url.to_string() })?;
+
+        // progress may show up on either stream depending on the yt-dlp version
+        let last_percent = AtomicI64::new(-1);
+        let converting = AtomicBool::new(false);
+        let mut info_lines = Vec::new();
+        let mut error_lines = Vec::new();
+
+        let read_stdout = async {
+            let mut lines = BufReader::new(stdout).lines();
+            while let Ok(Some(line)) = lines.next_line().await {
+                if !self.handle_progress(params, &line, &last_percent, &converting).await {
+                    info_lines.push(line);
+                }
+            }
+        };
+        let read_stderr = async {
+            let mut lines = BufReader::new(stderr).lines();
+            while let Ok(Some(line)) = lines.next_line().await {
+                if !self.handle_progress(params, &line, &last_percent, &converting).await && line.starts_with("ERROR") {
+                    error_lines.push(line);
+                }
+            }
+        };
+        tokio::join!(read_stdout, read_stderr);
+
+        let status = child.wait().await?;
         
         println!("processing-end: {}", url);
         
-        if !output.status.success() {
-            return Err(Error::DownloadFailed { url: url.to_string() });
+        if !status.success() {
+            return Err(FileManager::download_error(&url, &error_lines));
         }
 
+        let metadata = info_lines
+            .iter()
+            .rev()
+            .find_map(|line| serde_json::from_str::<Value>(line).ok())
+            .map(|info| TrackMetadata::from_info(&info))
+            .unwrap_or_default();
 
-        let change_file_name = Command::new("mv")
-            .arg(format!("{}/{}.ogg.opus", converted_dir, uuid))
-            .arg(format!("{}/{}.ogg", converted_dir, uuid))
+        if !converting.load(Ordering::Relaxed) {
+            self.notify(params, DownloadStage::Converting).await;
+        }
+
+        Ok(metadata)
+    }
+
+    // only failures that look like the network or the site having trouble are worth
+    // retrying, a private, removed or geo-blocked video fails the same way every time
+    fn download_error(url: &str, error_lines: &[String]) -> Error {
+        const TRANSIENT: [&str; 9] = [
+            "HTTP Error 5",
+            "HTTP Error 429",
+            "timed out",
+            "Connection reset",
+            "Connection refused",
+            "Temporary failure in name resolution",
+            "Name or service not known",
+            "Remote end closed connection",
+            "IncompleteRead",
+        ];
+
+        let transient = error_lines
+            .iter()
+            .any(|line| TRANSIENT.iter().any(|pattern| line.contains(pattern)));
+
+        if transient {
+            Error::DownloadInterrupted { url: url.to_string() }
+        } else {
+            Error::DownloadFailed { url: url.to_string() }
+        }
+    }
+
+    // turns a yt-dlp progress line into a downloading event, false for any other line
+    async fn handle_progress(&self, params: &FMDownloadParams, line: &str, last_percent: &AtomicI64, converting: &AtomicBool) -> bool {
+        let Some(progress) = line.trim().strip_prefix(PROGRESS_PREFIX) else {
+            return false;
+        };
+
+        let mut fields = progress.split_whitespace();
+        let status = fields.next().unwrap_or("");
+        let downloaded_bytes = fields.next().and_then(|v| v.parse::<f64>().ok()).unwrap_or(0.0) as u64;
+        let total_bytes = fields
+            .take(2)
+            .find_map(|v| v.parse::<f64>().ok())
+            .map(|v| v as u64)
+            .filter(|v| *v > 0);
+
+        // yt-dlp hands the file to ffmpeg once the download is finished
+        if status == "finished" {
+            if !converting.swap(true, Ordering::Relaxed) {
+                self.notify(params, DownloadStage::Converting).await;
+            }
+            return true;
+        }
+
+        let percent = total_bytes
+            .map(|total| (downloaded_bytes as f64 / total as f64 * 100.0).min(100.0))
+            .unwrap_or(0.0);
+
+        // whole percents are plenty for a progress bar
+        if percent.floor() as i64 > last_percent.load(Ordering::Relaxed) {
+            last_percent.store(percent.floor() as i64, Ordering::Relaxed);
+            self.notify(params, DownloadStage::Downloading {
+                percent: (percent * 10.0).round() / 10.0,
+                downloaded_bytes,
+                total_bytes,
+            }).await;
+        }
+        true
+    }
+
+    // sends a typed event to the user's download_notify stream, if they are listening
+    pub async fn notify(&self, params: &FMDownloadParams, stage: DownloadStage) {
+        self.notify_user(&params.userid, DownloadEvent {
+            job_id: params.job_id,
+            url: params.url.clone(),
+            title: params.title.clone(),
+            stage,
+        }).await;
+    }
+
+    pub async fn notify_user(&self, user_id: &str, event: DownloadEvent) {
+        let Ok(sender) = self.get_sender_with_id(user_id.to_string()).await else {
+            return;
+        };
+
+        if let Ok(msg) = serde_json::to_string(&event) {
+            sender.send(msg).unwrap_or(0);
+        }
+    }
+
+
+    // transcodes a file received by the upload route and stores it like a downloaded one,
+    // the uploaded source file goes away with the guard on every path out of here
+    pub async fn process_upload(&self, params: FMDownloadParams, source: TempFile, format: String) -> Result<String> {
+
+        let user_id = params.userid.parse::<i32>().map_err(|_| Error::AuthFailInvalidToken)?;
+        self.quota.usage(&params.pool, user_id).await?.check(0)?;
+
+        let sem_clone = self.semaphore.clone();
+        let _permit = sem_clone.acquire().await.unwrap();
+
+        let uuid = uuid::Uuid::new_v4().to_string();
+        let output_path = format!("{}/{}.ogg", CONVERTED_DIR, uuid);
+
+        println!("processing-start: {}", params.url);
+        self.notify(&params, DownloadStage::Converting).await;
+        let output = Command::new(FFMPEG_PATH)
+            .args(["-hide_banner", "-nostats", "-y"])
+            .args(["-i", source.path()])
+            // drop embedded cover art
+            .arg("-vn")
+            .args(self.encode_args())
+            .arg(&output_path)
+            .stdout(Stdio::null())
             .output()
             .await?;
+        println!("processing-end: {}", params.url);
+
+        drop(source);
 
-        // upload the file to s3
-        let body = aws_sdk_s3::primitives::ByteStream::from_path(
-            format!("{}/{}.ogg", converted_dir, uuid)
-        ).await.unwrap();
+        if !output.status.success() {
+            tokio::fs::remove_file(&output_path).await;
+            return Err(Error::ConversionFailed { url: params.url.clone() });
+        }
 
-        // if upload failed, also remove the files
+        let metadata = TrackMetadata {
+            duration_ms: FileManager::parse_duration(&String::from_utf8_lossy(&output.stderr)),
+            original_format: Some(format),
+            ..Default::default()
+        };
 
-        self.s3_client
-            .put_object()
-            .bucket("antaresmusicshare")
-            .key(format!("{}.ogg", uuid))
-            .body(body)
-            .send()
-            .await
-            .map_err(
-                // delete files if upload failed
-                |e| {
-                    tokio::fs::remove_file(format!("{}/{}.ogg", converted_dir, uuid));
-                    Error::UploadFailed { msg: e.to_string() }
-                }
-            )?;
+        let key = self.store_converted(&params, &uuid, metadata).await?;
+
+        println!("task done: {}", params.url);
+        self.notify(&params, DownloadStage::Done { uuid: key.clone() }).await;
+        Ok(key)
+    }
+
+    // where the upload route writes the raw file before it is transcoded
+    pub async fn upload_path(format: &str) -> Result<String> {
+        if !Path::new(CONVERTED_DIR).exists() {
+            tokio::fs::create_dir_all(CONVERTED_DIR).await?;
+        }
+        Ok(format!("{}/{}.upload.{}", CONVERTED_DIR, uuid::Uuid::new_v4(), format))
+    }
+
+    // ffmpeg output options of the one encode every file goes through, loudness is
+    // normalized there so the lossy opus file is never encoded a second time
+    fn encode_args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        if self.normalize_loudness {
+            args.push("-af".to_string());
+            args.push(format!("loudnorm=I={}:TP=-1.5:LRA=11", self.loudness_target));
+            // loudnorm resamples internally, bring it back to opus' native rate
+            args.extend(["-ar", "48000"].map(String::from));
+        }
+        args.extend(["-c:a", "libopus", "-b:a", "128k", "-page_duration", "20000", "-f", "ogg"].map(String::from));
+        args
+    }
 
-        println!("upload done: {}", url);
+    // reads the input duration ffmpeg logs as "Duration: HH:MM:SS.xx"
+    pub fn parse_duration(ffmpeg_log: &str) -> Option<i32> {
+        let start = ffmpeg_log.find("Duration: ")? + "Duration: ".len();
+        let timestamp = ffmpeg_log[start..].split(',').next()?;
+
+        let mut parts = timestamp.trim().split(':');
+        let hours = parts.next()?.parse::<f64>().ok()?;
+        let minutes = parts.next()?.parse::<f64>().ok()?;
+        let seconds = parts.next()?.parse::<f64>().ok()?;
+
+        Some(((hours * 3600.0 + minutes * 60.0 + seconds) * 1000.0).round() as i32)
+    }
+
+    // shared tail of every ingest path: measure, upload to s3 and record the file,
+    // expects the converted file at ./converted/<uuid>.ogg and removes it when done.
+    // returns the storage key, which is an existing blob's when the content is known
+    pub async fn store_converted(&self, params: &FMDownloadParams, uuid: &str, metadata: TrackMetadata) -> Result<String> {
+
+        let userid: i32 = params.userid.clone().parse::<i32>().unwrap();
+
+        let file_path = format!("{}/{}.ogg", CONVERTED_DIR, uuid);
+        let on_attempt = |attempt| self.record_attempt(params, attempt);
+        let stats = match self.retry.conversion.run("loudness", || self.measure_loudness(&file_path, &params.url), on_attempt).await {
+            Ok(stats) => stats,
+            Err(e) => {
+                tokio::fs::remove_file(&file_path).await;
+                return Err(e);
+            }
+        };
+        let loudness: f64 = stats.input_i.parse().unwrap_or(f64::NEG_INFINITY);
+
+        // check the quota with the final size before spending the upload on it
+        let size_bytes = tokio::fs::metadata(&file_path).await.map(|m| m.len() as i64).unwrap_or(0);
+        if let Err(e) = self.quota.usage(&params.pool, userid).await.and_then(|usage| usage.check(size_bytes)) {
+            tokio::fs::remove_file(&file_path).await;
+            return Err(e);
+        }
+
+        // the same audio may already be stored under another url or by another user
+        let hash = match blobs::content_hash(&file_path, &params.url).await {
+            Ok(hash) => hash,
+            Err(e) => {
+                tokio::fs::remove_file(&file_path).await;
+                return Err(e);
+            }
+        };
+        if let Some(blob) = blobs::find_by_hash(&params.pool, &hash).await? {
+            tokio::fs::remove_file(&file_path).await;
+            blobs::link(&params.pool, &self.quota, params, &blob).await?;
+            return Ok(blob.storage_key);
+        }
+
+        // upload the file to storage, if upload failed, also remove the files
+        self.notify(params, DownloadStage::Uploading).await;
+        if let Err(e) = self.retry.upload.run("upload", || self.storage.put(uuid, &file_path), on_attempt).await {
+            tokio::fs::remove_file(&file_path).await;
+            return Err(e);
+        }
+
+        println!("upload done: {}", params.url);
         // delete the files in convert
-        tokio::fs::remove_file(format!("{}/{}.ogg", converted_dir, uuid)).await?;
+        tokio::fs::remove_file(format!("{}/{}.ogg", CONVERTED_DIR, uuid)).await?;
+
+        let source_url = blobs::normalize_source_url(&params.url);
+        let blob = match blobs::insert(&params.pool, NewBlob {
+            storage_key: uuid,
+            source_url: source_url.as_deref(),
+            content_hash: &hash,
+            size_bytes,
+            loudness_lufs: loudness.is_finite().then_some(loudness),
+            metadata: &metadata,
+        }).await {
+            Ok(blob) => blob,
+            Err(e) => {
+                self.delete_stored(uuid).await;
+                return Err(e);
+            }
+        };
 
-        match sqlx::query(
-            "
-            INSERT INTO files (user_id, url, uuid, name)
-            VALUES ($1, $2, $3, $4)
-            ")
-            .bind(userid)
-            .bind(url.clone())
-            .bind(uuid.clone())
-            .bind(title)
-            .execute(&pool)
-            .await {
-                Ok(_) => {
-                }
-                Err(e) => {
-                    return Err(Error::DatabaseWriteError { msg: e.to_string() });
+        // a concurrent download stored the same audio first, keep theirs
+        if blob.storage_key != uuid {
+            self.delete_stored(uuid).await;
+        }
+
+        // concurrent downloads of the same user may have used up the quota in the meantime
+        if let Err(e) = blobs::link(&params.pool, &self.quota, params, &blob).await {
+            if let Ok(mut tx) = params.pool.begin().await {
+                if let Ok(Some(key)) = blobs::remove_if_unreferenced(&mut tx, blob.blob_id).await {
+                    if tx.commit().await.is_ok() {
+                        self.delete_stored(&key).await;
+                    }
                 }
             }
-        
-        let sender = self.get_sender_with_id(params.userid.clone()).await?;
+            return Err(e);
+        }
 
-        println!("task done: {}", url);
-        sender.send("check".to_string()).unwrap_or(0);
-        Ok(())
+        Ok(blob.storage_key)
+    }
+
+    // cleanup of an object nothing references, a failure leaves an orphan behind so it is logged
+    async fn delete_stored(&self, key: &str) {
+        if let Err(e) = self.storage.delete(key).await {
+            eprintln!("could not delete {} from storage: {:?}", key, e);
+        }
+    }
+
+    // removes the user's file row, returns the storage key to delete once no user
+    // references the stored object anymore
+    pub async fn delete_file_record(&self, pool: &PgPool, user_id: i32, uuid: &str) -> Result<Option<String>> {
+        blobs::unlink(pool, user_id, uuid).await
     }
 
+    // metadata of a file the user may play: their own, or one another user made public.
+    // anything else is reported as missing so private keys cannot be probed
+    pub async fn get_playable_file(pool: &PgPool, user_id: i32, key: &str) -> Result<(String, TrackMetadata)> {
+
+        let row = sqlx::query(
+            "SELECT COALESCE(name, '') AS name, duration_ms, artist, thumbnail, original_format
+             FROM files
+             WHERE uuid = $1 AND (user_id = $2 OR is_public)
+             ORDER BY (user_id = $2) DESC
+             LIMIT 1"
+        )
+        .bind(key)
+        .bind(user_id)
+        .fetch_optional(pool)
+        .await
+        .map_err(|e| Error::DBError { source: e.to_string() })?
+        .ok_or(Error::ContentNotFound { msg: key.to_string() })?;
+
+        Ok((row.get("name"), TrackMetadata::from_row(&row)))
+    }
+
+    // metadata of the given file keys, keys without a files row are left out
+    pub async fn get_track_metadata(pool: &PgPool, keys: Vec<String>) -> Result<HashMap<String, TrackMetadata>> {
+
+        let rows = sqlx::query(
+            "SELECT uuid, duration_ms, artist, thumbnail, original_format FROM files WHERE uuid = ANY($1)"
+        )
+        .bind(keys)
+        .fetch_all(pool)
+        .await
+        .map_err(|e| Error::DBError { source: e.to_string() })?;
+
+        Ok(rows.iter().map(|row| {
+            (row.get::<String, &str>("uuid"), TrackMetadata::from_row(row))
+        }).collect())
+    }
+
+    // only measures the integrated loudness of the file, source_url is what errors report
+    pub async fn measure_loudness(&self, file_path: &str, source_url: &str) -> Result<LoudnessStats> {
+
+        let output = Command::new(FFMPEG_PATH)
+            .args(["-hide_banner", "-nostats"])
+            .args(["-i", file_path])
+            .args([
+                "-af",
+                &format!("loudnorm=I={}:TP=-1.5:LRA=11:print_format=json", self.loudness_target),
+            ])
+            .args(["-f", "null", "-"])
+            .kill_on_drop(true)
+            .output()
+            .await?;
+
+        if !output.status.success() {
+            return Err(Error::ConversionFailed { url: source_url.to_string() });
+        }
+
+        // the json summary is the last block ffmpeg writes to stderr
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let json = stderr
+            .rfind('{')
+            .and_then(|start| stderr[start..].rfind('}').map(|end| &stderr[start..start + end + 1]))
+            .ok_or(Error::ConversionFailed { url: source_url.to_string() })?;
+
+        serde_json::from_str::<LoudnessStats>(json)
+            .map_err(|_| Error::ConversionFailed { url: source_url.to_string() })
+    }
 
     pub async fn is_live(url: String) -> Result<(bool)> {
 
@@ -316,16 +834,18 @@ impl FileManager {
             .arg("bestaudio") // Specify best audio format
             .arg("--print")
             .arg("filesize") // Print the estimated file size
-            .arg(youtube_url)
+            .arg(&youtube_url)
+            .kill_on_drop(true)
             .output()
             .await?;
 
+        // a failed probe is classified like a failed download, so the probe retry policy
+        // retries network trouble and fails the job right away for anything permanent
         if !output.status.success() {
-            eprintln!(
-                "Failed to fetch file size. Error: {}",
-                String::from_utf8_lossy(&output.stderr)
-            );
-            return Ok(());
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            eprintln!("Failed to fetch file size. Error: {}", stderr);
+            let error_lines: Vec<String> = stderr.lines().map(|line| line.to_string()).collect();
+            return Err(FileManager::download_error(&youtube_url, &error_lines));
         }
 
         let limit = env::var("MAX_FILE_SIZE")
@@ -348,7 +868,8 @@ impl FileManager {
     pub async fn get_sender_with_id(&self, id: String) -> Result<broadcast::Sender<String>> {
         let mut processing_user = self.processing_user.lock().await;
         let sender = processing_user.get(&id)
-            .map(|f| f.clone()).unwrap();
+            .map(|f| f.clone())
+            .ok_or(Error::SSEError { msg: "No download listener for user".to_string() })?;
         
         Ok(sender)
     }
@@ -361,18 +882,91 @@ impl FileManager {
 
     pub async fn delete_file(&self, uuid: String) -> Result<()> {
 
-        // delete the file from s3
-        self.s3_client
-            .delete_object()
-            .bucket("antaresmusicshare")
-            .key(format!("{}.ogg", uuid))
-            .send()
-            .await.map_err(
-                |e| {
-                    Error::S3Error { msg: e.to_string() }
-                }
-            )?;
+        // delete the file from storage
+        self.storage.delete(&uuid).await?;
 
         Ok(())
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::LocalStorage;
+
+    // one second of a 440hz sine, 16 bit mono at 48khz
+    fn sine_wav() -> Vec<u8> {
+        let samples: Vec<i16> = (0..48000)
+            .map(|i| ((i as f64 * 440.0 * 2.0 * std::f64::consts::PI / 48000.0).sin() * 8000.0) as i16)
+            .collect();
+        let data_len = (samples.len() * 2) as u32;
+
+        let mut wav = Vec::new();
+        wav.extend_from_slice(b"RIFF");
+        wav.extend_from_slice(&(36 + data_len).to_le_bytes());
+        wav.extend_from_slice(b"WAVEfmt ");
+        wav.extend_from_slice(&16u32.to_le_bytes());
+        wav.extend_from_slice(&1u16.to_le_bytes());
+        wav.extend_from_slice(&1u16.to_le_bytes());
+        wav.extend_from_slice(&48000u32.to_le_bytes());
+        wav.extend_from_slice(&96000u32.to_le_bytes());
+        wav.extend_from_slice(&2u16.to_le_bytes());
+        wav.extend_from_slice(&16u16.to_le_bytes());
+        wav.extend_from_slice(b"data");
+        wav.extend_from_slice(&data_len.to_le_bytes());
+        for sample in samples {
+            wav.extend_from_slice(&sample.to_le_bytes());
+        }
+        wav
+    }
+
+    async fn test_user(pool: &PgPool) -> i32 {
+        sqlx::query("INSERT INTO users (oauth_type, sub, name, picture) VALUES ('stub', $1, 'test', '') RETURNING user_id")
+            .bind(uuid::Uuid::new_v4().to_string())
+            .fetch_one(pool)
+            .await
+            .unwrap()
+            .get("user_id")
+    }
+
+    #[tokio::test]
+    #[ignore = "needs ./libs/ffmpeg and a database at DATABASE_URL_DEV"]
+    async fn same_audio_ingested_twice_links_to_the_first_blob() {
+        let pool = crate::db::establish_connection().await.unwrap();
+        let dir = std::env::temp_dir().join(format!("musicshare-blobs-{}", uuid::Uuid::new_v4()));
+        let storage = LocalStorage::new(dir.to_string_lossy().to_string()).await.unwrap();
+        let fm = FileManager::new(Arc::new(storage)).await.unwrap();
+
+        let mut keys = Vec::new();
+        let mut users = Vec::new();
+        // two users, the same user would get DuplicateContent for the second file
+        for _ in 0..2 {
+            let user_id = test_user(&pool).await;
+            let source = TempFile::new(FileManager::upload_path("wav").await.unwrap());
+            tokio::fs::write(source.path(), sine_wav()).await.unwrap();
+
+            let params = FMDownloadParams {
+                url: "upload://sine.wav".to_string(),
+                title: "sine".to_string(),
+                userid: user_id.to_string(),
+                pool: pool.clone(),
+                job_id: None,
+            };
+            keys.push(fm.process_upload(params, source, "wav".to_string()).await.unwrap());
+            users.push(user_id);
+        }
+
+        assert_eq!(keys[0], keys[1]);
+        let files: i64 = sqlx::query("SELECT COUNT(*) AS count FROM files WHERE uuid = $1 AND user_id = ANY($2)")
+            .bind(&keys[0])
+            .bind(&users)
+            .fetch_one(&pool)
+            .await
+            .unwrap()
+            .get("count");
+        assert_eq!(files, 2);
+
+        sqlx::query("DELETE FROM users WHERE user_id = ANY($1)").bind(&users).execute(&pool).await.unwrap();
+        sqlx::query("DELETE FROM blobs WHERE storage_key = $1").bind(&keys[0]).execute(&pool).await.unwrap();
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+}
@@ -0,0 +1,336 @@
+use crate::utils::error::{ Error, Result };
+use crate::media::file_manager::{ FileManager, FMDownloadParams, DownloadStage };
+use crate::models::playlist::Playlist;
+use serde::Serialize;
+use sqlx::PgPool;
+use sqlx::Row;
+use sqlx::postgres::PgRow;
+use futures::FutureExt;
+use std::collections::HashMap;
+use std::env;
+use std::sync::{ Arc, Mutex };
+use tokio::sync::{ Notify, Semaphore };
+
+// columns every job query selects, timestamps as unix seconds
+const JOB_COLUMNS: &str = "
+    job_id, url, title, status, attempts, error_code, error, file_uuid,
+    EXTRACT(EPOCH FROM created_at)::BIGINT AS created_at,
+    EXTRACT(EPOCH FROM updated_at)::BIGINT AS updated_at
+";
+
+#[derive(Clone, Debug, Serialize)]
+pub struct DownloadJob {
+    pub job_id: i32,
+    pub url: String,
+    pub title: String,
+    // queued, running, succeeded, failed or cancelled
+    pub status: String,
+    // highest attempt any stage needed, see RetryPolicies
+    pub attempts: i32,
+    pub error_code: Option<String>,
+    pub error: Option<String>,
+    pub file_uuid: Option<String>,
+    pub created_at: i64,
+    pub updated_at: i64,
+}
+
+impl DownloadJob {
+    pub fn from_row(row: &PgRow) -> Self {
+        Self {
+            job_id: row.get("job_id"),
+            url: row.get("url"),
+            title: row.get("title"),
+            status: row.get("status"),
+            attempts: row.get("attempts"),
+            error_code: row.get("error_code"),
+            error: row.get("error"),
+            file_uuid: row.get("file_uuid"),
+            created_at: row.get("created_at"),
+            updated_at: row.get("updated_at"),
+        }
+    }
+}
+
+/// Runs downloads as rows in download_jobs, so their outcome survives the request
+/// that started them and unfinished jobs are picked up again after a restart.
+#[derive(Clone, Debug)]
+pub struct JobRunner {
+    pool: PgPool,
+    file_manager: FileManager,
+    // cancel signal of every job that has not finished yet
+    active: Arc<Mutex<HashMap<i32, Arc<Notify>>>>,
+    // jobs beyond MAX_CONCURRENT_JOBS (default 4) stay queued until a slot frees up
+    permits: Arc<Semaphore>,
+}
+
+impl JobRunner {
+    pub fn new(pool: PgPool, file_manager: FileManager) -> Self {
+        let max_concurrent_jobs = env::var("MAX_CONCURRENT_JOBS")
+            .unwrap_or("4".to_string())
+            .parse::<usize>()
+            .expect("MAX_CONCURRENT_JOBS must be a number");
+
+        Self {
+            pool,
+            file_manager,
+            active: Arc::new(Mutex::new(HashMap::new())),
+            permits: Arc::new(Semaphore::new(max_concurrent_jobs)),
+        }
+    }
+
+    // fails when new_jobs more downloads would not fit in the user's file quota,
+    // counting the jobs that are still queued or running
+    pub async fn check_headroom(&self, user_id: &str, new_jobs: usize) -> Result<()> {
+        let user_id = user_id.parse::<i32>().map_err(|_| Error::AuthFailInvalidToken)?;
+
+        let pending: i64 = sqlx::query(
+            "SELECT COUNT(*) AS pending FROM download_jobs WHERE user_id = $1 AND status IN ('queued', 'running')"
+        )
+        .bind(user_id)
+        .fetch_one(&self.pool)
+        .await
+        .map_err(|e| Error::DBError { source: e.to_string() })?
+        .get("pending");
+
+        self.file_manager.quota
+            .usage(&self.pool, user_id)
+            .await?
+            .check_files(pending + new_jobs as i64)
+    }
+
+    // records a queued job and starts it in the background
+    pub async fn submit(&self, user_id: &str, url: String, title: String) -> Result<i32> {
+        self.submit_for_playlist(user_id, url, title, None).await
+    }
+
+    // like submit, the finished file is then added to the playlist at (playlist_id, position)
+    pub async fn submit_for_playlist(
+        &self,
+        user_id: &str,
+        url: String,
+        title: String,
+        playlist: Option<(i32, i32)>,
+    ) -> Result<i32> {
+        let user_id = user_id.parse::<i32>().map_err(|_| Error::AuthFailInvalidToken)?;
+
+        let row = sqlx::query(
+            "INSERT INTO download_jobs (user_id, url, title, playlist_id, playlist_position)
+             VALUES ($1, $2, $3, $4, $5) RETURNING job_id"
+        )
+        .bind(user_id)
+        .bind(&url)
+        .bind(&title)
+        .bind(playlist.map(|(playlist_id, _)| playlist_id))
+        .bind(playlist.map(|(_, position)| position))
+        .fetch_one(&self.pool)
+        .await
+        .map_err(|e| Error::DBError { source: e.to_string() })?;
+
+        let job_id: i32 = row.get("job_id");
+        self.spawn(job_id, user_id, url, title);
+        Ok(job_id)
+    }
+
+    // jobs that were queued or running when the server stopped start over
+    pub async fn resume_pending(&self) -> Result<()> {
+        let rows = sqlx::query(
+            "UPDATE download_jobs SET status = 'queued', attempts = 0, updated_at = NOW()
+             WHERE status IN ('queued', 'running')
+             RETURNING job_id, user_id, url, title"
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(|e| Error::DBError { source: e.to_string() })?;
+
+        println!("->> {:<12} - resuming {} download jobs", "Jobs", rows.len());
+        for row in rows {
+            self.spawn(row.get("job_id"), row.get("user_id"), row.get("url"), row.get("title"));
+        }
+        Ok(())
+    }
+
+    fn spawn(&self, job_id: i32, user_id: i32, url: String, title: String) {
+        let cancel = Arc::new(Notify::new());
+        self.active.lock().unwrap().insert(job_id, cancel.clone());
+
+        let runner = self.clone();
+        tokio::spawn(async move {
+            // sent before waiting for a slot, so clients see the job while it waits
+            let params = runner.params(job_id, user_id, url, title);
+            runner.file_manager.notify(&params, DownloadStage::Queued).await;
+
+            let permit = tokio::select! {
+                permit = runner.permits.clone().acquire_owned() => Some(permit),
+                _ = cancel.notified() => None,
+            };
+            let result = match permit {
+                Some(_permit) => runner.run(job_id, user_id, params.clone(), cancel).await,
+                // cancelled before it got a slot
+                None => {
+                    runner.file_manager.notify(&params, DownloadStage::Cancelled).await;
+                    runner.set_status(job_id, "cancelled", None, None).await
+                }
+            };
+
+            if let Err(e) = result {
+                eprintln!("download job {} could not be recorded: {:?}", job_id, e);
+            }
+            runner.active.lock().unwrap().remove(&job_id);
+        });
+    }
+
+    fn params(&self, job_id: i32, user_id: i32, url: String, title: String) -> FMDownloadParams {
+        FMDownloadParams {
+            url,
+            title,
+            userid: user_id.to_string(),
+            pool: self.pool.clone(),
+            job_id: Some(job_id),
+        }
+    }
+
+    async fn run(&self, job_id: i32, user_id: i32, params: FMDownloadParams, cancel: Arc<Notify>) -> Result<()> {
+        self.set_status(job_id, "running", None, None).await?;
+
+        let result = self.file_manager.process_audio(params.clone(), cancel.clone()).await;
+
+        match result {
+            Ok(uuid) if self.cancelled_after_finish(job_id, &cancel) => {
+                self.roll_back(job_id, user_id, &uuid).await;
+                self.file_manager.notify(&params, DownloadStage::Cancelled).await;
+                self.set_status(job_id, "cancelled", None, None).await
+            }
+            Ok(uuid) => {
+                self.add_to_playlist(job_id, user_id, &uuid).await;
+                self.set_status(job_id, "succeeded", None, Some(uuid)).await
+            }
+            Err(Error::JobCancelled) => {
+                self.file_manager.notify(&params, DownloadStage::Cancelled).await;
+                self.set_status(job_id, "cancelled", None, None).await
+            }
+            Err(e) => {
+                eprintln!("download job {} failed: {:?}", job_id, e);
+                let (_, client_error) = e.client_status_and_error();
+                self.file_manager.notify(&params, DownloadStage::Failed {
+                    code: client_error.code(),
+                    reason: client_error.message().to_string(),
+                }).await;
+                self.set_status(job_id, "failed", Some(&e), None).await
+            }
+        }
+    }
+
+    // takes the job out of the cancellable set, then reports whether a cancel came in
+    // between the stored file and now. cancel() notifies under the same lock, so no
+    // cancel can slip in after this check
+    fn cancelled_after_finish(&self, job_id: i32, cancel: &Notify) -> bool {
+        let mut active = self.active.lock().unwrap();
+        active.remove(&job_id);
+        cancel.notified().now_or_never().is_some()
+    }
+
+    // undoes a stored file whose job was cancelled, so it does not count against the quota
+    async fn roll_back(&self, job_id: i32, user_id: i32, uuid: &str) {
+        match self.file_manager.delete_file_record(&self.pool, user_id, uuid).await {
+            Ok(Some(key)) => {
+                if let Err(e) = self.file_manager.storage.delete(&key).await {
+                    eprintln!("download job {} could not delete {} from storage: {:?}", job_id, key, e);
+                }
+            }
+            Ok(None) => (),
+            Err(e) => eprintln!("download job {} could not roll back file {}: {:?}", job_id, uuid, e),
+        }
+    }
+
+    // a failure here leaves the download itself intact, so it is only logged
+    async fn add_to_playlist(&self, job_id: i32, user_id: i32, uuid: &str) {
+        let target = sqlx::query(
+            "SELECT playlist_id, playlist_position FROM download_jobs
+             WHERE job_id = $1 AND playlist_id IS NOT NULL"
+        )
+        .bind(job_id)
+        .fetch_optional(&self.pool)
+        .await;
+
+        let (playlist_id, position): (i32, Option<i32>) = match target {
+            Ok(Some(row)) => (row.get("playlist_id"), row.get("playlist_position")),
+            Ok(None) => return,
+            Err(e) => {
+                eprintln!("download job {} playlist lookup failed: {:?}", job_id, e);
+                return;
+            }
+        };
+
+        if let Err(e) = Playlist::add_item(&self.pool, user_id, playlist_id, position.unwrap_or(0), uuid).await {
+            eprintln!("download job {} could not be added to playlist {}: {:?}", job_id, playlist_id, e);
+        }
+    }
+
+    // only the client side of an error is stored, the job list is shown to users
+    async fn set_status(&self, job_id: i32, status: &str, error: Option<&Error>, file_uuid: Option<String>) -> Result<()> {
+        let client_error = error.map(|e| e.client_status_and_error().1);
+
+        sqlx::query(
+            "UPDATE download_jobs
+             SET status = $2, error_code = $3, error = $4, file_uuid = $5, updated_at = NOW()
+             WHERE job_id = $1"
+        )
+        .bind(job_id)
+        .bind(status)
+        .bind(client_error.as_ref().map(|e| e.code()))
+        .bind(client_error.as_ref().map(|e| e.message()))
+        .bind(file_uuid)
+        .execute(&self.pool)
+        .await
+        .map_err(|e| Error::DBError { source: e.to_string() })?;
+
+        Ok(())
+    }
+
+    // stops a queued or running job, the job task records the cancellation itself
+    pub async fn cancel(&self, user_id: &str, job_id: i32) -> Result<()> {
+        let job = self.get(user_id, job_id).await?;
+
+        // notified while holding the lock, see cancelled_after_finish
+        let active = self.active.lock().unwrap();
+        match active.get(&job_id) {
+            Some(cancel) if job.status == "queued" || job.status == "running" => {
+                cancel.notify_one();
+                Ok(())
+            }
+            _ => Err(Error::JobFinished { id: job_id }),
+        }
+    }
+
+    pub async fn list(&self, user_id: &str) -> Result<Vec<DownloadJob>> {
+        let user_id = user_id.parse::<i32>().map_err(|_| Error::AuthFailInvalidToken)?;
+
+        let rows = sqlx::query(&format!(
+            "SELECT {} FROM download_jobs WHERE user_id = $1 ORDER BY job_id DESC LIMIT 100",
+            JOB_COLUMNS
+        ))
+        .bind(user_id)
+        .fetch_all(&self.pool)
+        .await
+        .map_err(|e| Error::DBError { source: e.to_string() })?;
+
+        Ok(rows.iter().map(DownloadJob::from_row).collect())
+    }
+
+    pub async fn get(&self, user_id: &str, job_id: i32) -> Result<DownloadJob> {
+        let user_id = user_id.parse::<i32>().map_err(|_| Error::AuthFailInvalidToken)?;
+
+        let row = sqlx::query(&format!(
+            "SELECT {} FROM download_jobs WHERE job_id = $1 AND user_id = $2",
+            JOB_COLUMNS
+        ))
+        .bind(job_id)
+        .bind(user_id)
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(|e| Error::DBError { source: e.to_string() })?
+        .ok_or(Error::JobNotFound { id: job_id })?;
+
+        Ok(DownloadJob::from_row(&row))
+    }
+}
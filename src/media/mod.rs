@@ -1,2 +1,5 @@
 pub mod broadcaster;
-pub mod file_manager;
\ No newline at end of file
+pub mod file_manager;
+pub mod jobs;
+pub mod quota;
+pub mod blobs;
\ No newline at end of file
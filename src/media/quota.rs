@@ -0,0 +1,112 @@
+use crate::utils::error::{ Error, Result };
+use serde::Serialize;
+use sqlx::{ PgPool, Postgres, Row, Transaction };
+use std::env;
+use dotenvy::dotenv;
+
+/// Limits on what a user may keep in storage. users.max_files and users.max_bytes
+/// override the QUOTA_MAX_FILES and QUOTA_MAX_BYTES defaults for single users.
+#[derive(Clone, Debug)]
+pub struct Quota {
+    pub max_files: i64,
+    pub max_bytes: i64,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct QuotaUsage {
+    pub files: i64,
+    pub bytes: i64,
+    pub max_files: i64,
+    pub max_bytes: i64,
+}
+
+impl QuotaUsage {
+    pub fn check(&self, extra_bytes: i64) -> Result<()> {
+        if self.files + 1 > self.max_files {
+            return Err(Error::QuotaExceeded { kind: "files".to_string(), limit: self.max_files });
+        }
+        if self.bytes + extra_bytes > self.max_bytes {
+            return Err(Error::QuotaExceeded { kind: "bytes".to_string(), limit: self.max_bytes });
+        }
+        Ok(())
+    }
+
+    // room for extra_files more files, e.g. downloads that have not finished yet
+    pub fn check_files(&self, extra_files: i64) -> Result<()> {
+        if self.files + extra_files > self.max_files {
+            return Err(Error::QuotaExceeded { kind: "files".to_string(), limit: self.max_files });
+        }
+        Ok(())
+    }
+}
+
+const USAGE_QUERY: &str = "
+    SELECT COALESCE(number_of_files, 0)::BIGINT AS files, total_bytes AS bytes,
+           max_files::BIGINT AS max_files, max_bytes
+    FROM users WHERE user_id = $1
+";
+
+impl Quota {
+    pub fn from_env() -> Self {
+        dotenv().ok();
+
+        Self {
+            max_files: env::var("QUOTA_MAX_FILES")
+                .unwrap_or("500".to_string())
+                .parse::<i64>()
+                .expect("QUOTA_MAX_FILES must be a number"),
+            max_bytes: env::var("QUOTA_MAX_BYTES")
+                .unwrap_or("2000000000".to_string())
+                .parse::<i64>()
+                .expect("QUOTA_MAX_BYTES must be a number"),
+        }
+    }
+
+    fn usage_from_row(&self, row: &sqlx::postgres::PgRow) -> QuotaUsage {
+        QuotaUsage {
+            files: row.get("files"),
+            bytes: row.get("bytes"),
+            max_files: row.get::<Option<i64>, &str>("max_files").unwrap_or(self.max_files),
+            max_bytes: row.get::<Option<i64>, &str>("max_bytes").unwrap_or(self.max_bytes),
+        }
+    }
+
+    pub async fn usage(&self, pool: &PgPool, user_id: i32) -> Result<QuotaUsage> {
+        let row = sqlx::query(USAGE_QUERY)
+            .bind(user_id)
+            .fetch_one(pool)
+            .await
+            .map_err(|e| Error::DBError { source: e.to_string() })?;
+
+        Ok(self.usage_from_row(&row))
+    }
+
+    // same as usage, but locks the user row until the transaction ends
+    pub async fn usage_for_update(&self, tx: &mut Transaction<'_, Postgres>, user_id: i32) -> Result<QuotaUsage> {
+        let row = sqlx::query(&format!("{} FOR UPDATE", USAGE_QUERY))
+            .bind(user_id)
+            .fetch_one(&mut **tx)
+            .await
+            .map_err(|e| Error::DBError { source: e.to_string() })?;
+
+        Ok(self.usage_from_row(&row))
+    }
+
+    // counters are only ever changed together with the files rows they count
+    pub async fn add_usage(tx: &mut Transaction<'_, Postgres>, user_id: i32, files: i64, bytes: i64) -> Result<()> {
+        sqlx::query(
+            "UPDATE users
+             SET number_of_files = GREATEST(COALESCE(number_of_files, 0) + $2, 0),
+                 total_bytes = GREATEST(total_bytes + $3, 0)
+             WHERE user_id = $1"
+        )
+        .bind(user_id)
+        .bind(files as i32)
+        .bind(bytes)
+        .execute(&mut **tx)
+        .await
+        .map_err(|e| Error::DBError { source: e.to_string() })?;
+
+        Ok(())
+    }
+}
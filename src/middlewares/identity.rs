@@ -0,0 +1,271 @@
+use async_trait::async_trait;
+use serde_json::Value;
+use sqlx::PgPool;
+use sqlx::Row;
+use std::collections::HashMap;
+use std::env;
+use std::sync::Arc;
+use dotenvy::dotenv;
+
+use argon2::{ Argon2, PasswordHash, PasswordHasher, PasswordVerifier };
+use argon2::password_hash::SaltString;
+use argon2::password_hash::rand_core::OsRng;
+
+use crate::utils::error::{ Error, Result };
+
+const GOOGLE_USERINFO_URL: &str = "https://www.googleapis.com/userinfo/v2/me";
+
+/// A user as reported by an identity provider, before it is matched to a users row.
+#[derive(Clone, Debug)]
+pub struct Identity {
+    pub subject: String,
+    pub name: String,
+    pub picture: String,
+}
+
+/// Turns the token a client presents into an identity. The provider name is stored
+/// as users.oauth_type, so subjects only need to be unique per provider.
+#[async_trait]
+pub trait IdentityProvider: std::fmt::Debug + Send + Sync {
+    fn name(&self) -> &str;
+
+    async fn identify(&self, pool: &PgPool, token: &str) -> Result<Identity>;
+}
+
+// fetches a userinfo document with the token as bearer auth
+async fn fetch_userinfo(url: &str, token: &str) -> Result<Value> {
+    let client = reqwest::Client::new();
+    let response = client
+        .get(url)
+        .bearer_auth(token)
+        .send()
+        .await
+        .map_err(|_| Error::AuthFailInvalidToken)?;
+
+    if !response.status().is_success() {
+        return Err(Error::AuthFailInvalidToken);
+    }
+
+    response
+        .json::<Value>()
+        .await
+        .map_err(|_| Error::AuthFailInvalidToken)
+}
+
+fn identity_from_userinfo(info: &Value, subject_claim: &str) -> Result<Identity> {
+    let subject = info.get(subject_claim).and_then(Value::as_str).unwrap_or("");
+    if subject.is_empty() {
+        return Err(Error::AuthFailInvalidToken);
+    }
+
+    Ok(Identity {
+        subject: subject.to_string(),
+        name: info.get("name").and_then(Value::as_str).unwrap_or("").to_string(),
+        picture: info.get("picture").and_then(Value::as_str).unwrap_or("").to_string(),
+    })
+}
+
+/// Google oauth access tokens, checked against the v2 userinfo endpoint.
+#[derive(Debug)]
+pub struct GoogleProvider;
+
+#[async_trait]
+impl IdentityProvider for GoogleProvider {
+    fn name(&self) -> &str {
+        "google"
+    }
+
+    async fn identify(&self, _pool: &PgPool, token: &str) -> Result<Identity> {
+        let info = fetch_userinfo(GOOGLE_USERINFO_URL, token).await?;
+        identity_from_userinfo(&info, "id")
+    }
+}
+
+/// Any OpenID Connect provider, using the standard sub/name/picture userinfo claims.
+#[derive(Debug)]
+pub struct OidcProvider {
+    pub name: String,
+    pub userinfo_url: String,
+}
+
+#[async_trait]
+impl IdentityProvider for OidcProvider {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    async fn identify(&self, _pool: &PgPool, token: &str) -> Result<Identity> {
+        let info = fetch_userinfo(&self.userinfo_url, token).await?;
+        identity_from_userinfo(&info, "sub")
+    }
+}
+
+/// Username and password accounts stored in the users table, the token is
+/// "username:password" as sent with http basic auth.
+#[derive(Debug)]
+pub struct LocalProvider;
+
+impl LocalProvider {
+    pub fn hash_password(password: &str) -> Result<String> {
+        let salt = SaltString::generate(&mut OsRng);
+        Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .map(|hash| hash.to_string())
+            .map_err(|_| Error::LoginFail)
+    }
+
+    pub async fn register(pool: &PgPool, username: &str, password: &str, name: &str) -> Result<()> {
+        let password_hash = LocalProvider::hash_password(password)?;
+
+        let result = sqlx::query(
+            "INSERT INTO users (oauth_type, sub, name, picture, password_hash)
+             VALUES ('local', $1, $2, '', $3)
+             ON CONFLICT (oauth_type, sub) DO NOTHING",
+        )
+        .bind(username)
+        .bind(name)
+        .bind(password_hash)
+        .execute(pool)
+        .await
+        .map_err(|e| Error::DBError {
+            source: format!("{:?}", e),
+        })?;
+
+        if result.rows_affected() == 0 {
+            return Err(Error::UserExists);
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl IdentityProvider for LocalProvider {
+    fn name(&self) -> &str {
+        "local"
+    }
+
+    async fn identify(&self, pool: &PgPool, token: &str) -> Result<Identity> {
+        let (username, password) = token.split_once(':').ok_or(Error::AuthFailInvalidToken)?;
+
+        let row = sqlx::query(
+            "SELECT name, picture, password_hash FROM users WHERE oauth_type = 'local' AND sub = $1",
+        )
+        .bind(username)
+        .fetch_optional(pool)
+        .await
+        .map_err(|e| Error::DBError {
+            source: format!("{:?}", e),
+        })?
+        .ok_or(Error::LoginFail)?;
+
+        let password_hash: String = row.get("password_hash");
+        let parsed = PasswordHash::new(&password_hash).map_err(|_| Error::LoginFail)?;
+        Argon2::default()
+            .verify_password(password.as_bytes(), &parsed)
+            .map_err(|_| Error::LoginFail)?;
+
+        Ok(Identity {
+            subject: username.to_string(),
+            name: row.get("name"),
+            picture: row.get("picture"),
+        })
+    }
+}
+
+/// Accepts any token and uses it as the subject, for development and tests only.
+#[derive(Debug)]
+pub struct StubProvider;
+
+#[async_trait]
+impl IdentityProvider for StubProvider {
+    fn name(&self) -> &str {
+        "stub"
+    }
+
+    async fn identify(&self, _pool: &PgPool, token: &str) -> Result<Identity> {
+        if token.is_empty() {
+            return Err(Error::AuthFailInvalidToken);
+        }
+
+        Ok(Identity {
+            subject: token.to_string(),
+            name: token.to_string(),
+            picture: "".to_string(),
+        })
+    }
+}
+
+/// The enabled providers, looked up by the name a request asks for.
+#[derive(Clone, Debug)]
+pub struct IdentityProviders {
+    providers: HashMap<String, Arc<dyn IdentityProvider>>,
+    default: String,
+}
+
+impl IdentityProviders {
+    pub fn new(default: &str) -> Self {
+        Self {
+            providers: HashMap::new(),
+            default: default.to_string(),
+        }
+    }
+
+    pub fn with(mut self, provider: Arc<dyn IdentityProvider>) -> Self {
+        self.providers.insert(provider.name().to_string(), provider);
+        self
+    }
+
+    /// Builds the providers listed in IDENTITY_PROVIDERS (default "google"),
+    /// the first one listed is used when a request does not name one.
+    pub fn from_env() -> Result<Self> {
+        dotenv().ok();
+
+        let names = env::var("IDENTITY_PROVIDERS").unwrap_or("google".to_string());
+        let names: Vec<&str> = names.split(',').map(str::trim).filter(|n| !n.is_empty()).collect();
+        let oidc_name = env::var("OIDC_NAME").unwrap_or("oidc".to_string());
+
+        let mut providers = IdentityProviders::new("google");
+        for (i, name) in names.into_iter().enumerate() {
+            let provider: Arc<dyn IdentityProvider> = match name {
+                "google" => Arc::new(GoogleProvider),
+                "local" => Arc::new(LocalProvider),
+                "stub" => Arc::new(StubProvider),
+                "oidc" => {
+                    // the name is what requests and users.oauth_type use, it may not shadow a built-in provider
+                    if ["google", "local", "stub"].contains(&oidc_name.as_str()) {
+                        return Err(Error::ConfigError {
+                            msg: format!("OIDC_NAME '{}' collides with a built-in identity provider", oidc_name),
+                        });
+                    }
+                    let userinfo_url = env::var("OIDC_USERINFO_URL").map_err(|_| Error::ConfigError {
+                        msg: "OIDC_USERINFO_URL must be set when the oidc provider is enabled".to_string(),
+                    })?;
+                    Arc::new(OidcProvider { name: oidc_name.clone(), userinfo_url })
+                },
+                other => {
+                    return Err(Error::ConfigError {
+                        msg: format!("Unknown identity provider in IDENTITY_PROVIDERS: {}", other),
+                    });
+                },
+            };
+
+            if i == 0 {
+                providers.default = provider.name().to_string();
+            }
+            providers = providers.with(provider);
+        }
+
+        Ok(providers)
+    }
+
+    pub fn get(&self, name: Option<&str>) -> Result<Arc<dyn IdentityProvider>> {
+        self.providers
+            .get(name.unwrap_or(&self.default))
+            .cloned()
+            .ok_or(Error::AuthFailInvalidToken)
+    }
+
+    pub fn has(&self, name: &str) -> bool {
+        self.providers.contains_key(name)
+    }
+}
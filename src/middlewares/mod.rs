@@ -1,3 +1,10 @@
 pub mod mw;
+pub mod identity;
+pub mod token_cache;
+pub mod session_token;
 
 pub const AUTH_TOKEN: &str = "google_access_token";
+pub const AUTH_PROVIDER: &str = "auth_provider";
+pub const AUTH_PROVIDER_HEADER: &str = "x-auth-provider";
+pub const SESSION_TOKEN: &str = "session_token";
+pub const REFRESH_TOKEN: &str = "refresh_token";
@@ -1,9 +1,11 @@
 use axum::middleware::Next;
-use axum::http::Request;
+use axum::http::{ HeaderMap, Request };
+use axum::http::header::AUTHORIZATION;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
 use axum::http::request::Parts;
 use axum::body::Body;
 use axum::response::Response;
-use serde_json::Value;
 
 use async_trait::async_trait;
 use axum::extract::{ FromRequestParts, State };
@@ -13,7 +15,10 @@ use sqlx::PgPool;
 use sqlx::Row;
 
 use crate::utils::error::{ Error, Result };
-use crate::middlewares::AUTH_TOKEN;
+use crate::middlewares::{ AUTH_TOKEN, AUTH_PROVIDER, AUTH_PROVIDER_HEADER, SESSION_TOKEN };
+use crate::middlewares::identity::{ IdentityProvider, IdentityProviders };
+use crate::middlewares::token_cache::TokenCache;
+use crate::middlewares::session_token::SessionTokens;
 use crate::ctx::Ctx;
 use crate::models::SessionController;
 use tower_cookies::{Cookie, Cookies};
@@ -38,124 +43,144 @@ pub async fn mw_optional_auth(
     Ok(next.run(req).await)
 }
 
-/// Helper: given a pool and auth token, fetch the user info from Google and then
-/// retrieve or insert the corresponding user into the database.
-async fn resolve_ctx_with_auth_token(pool: &PgPool, auth_token: &str) -> Result<Ctx> {
-    let client = reqwest::Client::new();
-    let response = client
-        .get("https://www.googleapis.com/userinfo/v2/me")
-        .bearer_auth(auth_token)
-        .send()
-        .await
-        .map_err(|_| Error::AuthFailInvalidToken)?;
-
-    let info = response
-        .json::<Value>()
-        .await
-        .map_err(|_| Error::AuthFailInvalidToken)?;
-
-    let id = info.get("id").and_then(Value::as_str).unwrap_or("");
-    if id.is_empty() {
-        return Err(Error::AuthFailInvalidToken);
+/// Helper: given a pool and auth token, ask the selected identity provider who the
+/// token belongs to and then retrieve or insert the corresponding user into the database.
+async fn resolve_ctx_with_auth_token(
+    pool: &PgPool,
+    provider: &dyn IdentityProvider,
+    auth_token: &str,
+) -> Result<Ctx> {
+    let identity = provider.identify(pool, auth_token).await?;
+
+    // Insert the user on first login, subjects are only unique per provider.
+    let row = sqlx::query(
+        "INSERT INTO users (oauth_type, sub, name, picture)
+         VALUES ($1, $2, $3, $4)
+         ON CONFLICT (oauth_type, sub) DO UPDATE SET oauth_type = EXCLUDED.oauth_type
+         RETURNING user_id, name, picture",
+    )
+    .bind(provider.name())
+    .bind(&identity.subject)
+    .bind(&identity.name)
+    .bind(&identity.picture)
+    .fetch_one(pool)
+    .await
+    .map_err(|e| Error::DBError {
+        source: format!("{:?}", e),
+    })?;
+
+    let userid: i32 = row.get("user_id");
+    let db_name: String = row.get("name");
+    let db_picture: String = row.get("picture");
+    Ok(Ctx::new(userid.to_string(), db_name, db_picture))
+}
+
+/// Helper: resolve through the token cache, only going to the provider on a miss.
+/// Local credentials are always checked against the stored password hash.
+pub async fn resolve_ctx_cached(
+    pool: &PgPool,
+    cache: &TokenCache,
+    provider: &dyn IdentityProvider,
+    auth_token: &str,
+) -> Result<Ctx> {
+    if let Some(ctx) = cache.get(provider.name(), auth_token) {
+        return Ok(ctx);
     }
-    let name = info.get("name").and_then(Value::as_str).unwrap_or("");
-    let picture = info.get("picture").and_then(Value::as_str).unwrap_or("");
-
-    // Query for an existing user.
-    let rows = sqlx::query("SELECT * FROM users WHERE sub = $1")
-        .bind(id)
-        .fetch_all(pool)
-        .await
-        .map_err(|e| Error::DBError {
-            source: format!("{:?}", e),
-        })?;
-
-    if rows.len() == 1 {
-        let row = &rows[0];
-        let userid: i32 = row.get("user_id");
-        let db_name: String = row.get("name");
-        let db_picture: String = row.get("picture");
-        Ok(Ctx::new(userid.to_string(), db_name, db_picture))
-    } else if rows.is_empty() {
-        // Insert new user.
-        sqlx::query(
-            "INSERT INTO users (oauth_type, sub, name, picture)
-             VALUES ('google', $1, $2, $3)",
-        )
-        .bind(id)
-        .bind(name)
-        .bind(picture)
-        .execute(pool)
-        .await
-        .map_err(|e| Error::DBError {
-            source: format!("{:?}", e),
-        })?;
-
-        // Query again for the newly inserted user.
-        let rows = sqlx::query("SELECT * FROM users WHERE sub = $1")
-            .bind(id)
-            .fetch_all(pool)
-            .await
-            .map_err(|e| Error::DBError {
-                source: format!("{:?}", e),
-            })?;
-
-        if let Some(row) = rows.first() {
-            let userid: i32 = row.get("user_id");
-            let db_name: String = row.get("name");
-            let db_picture: String = row.get("picture");
-            Ok(Ctx::new(userid.to_string(), db_name, db_picture))
+
+    let ctx = resolve_ctx_with_auth_token(pool, provider, auth_token).await?;
+    cache.insert(provider.name(), auth_token, ctx.clone());
+    Ok(ctx)
+}
+
+/// Helper: pick the provider and token for a request. The token comes from the auth
+/// cookie or the Authorization header (Bearer, or Basic for local accounts), the
+/// provider from the provider cookie or X-Auth-Provider header.
+pub fn extract_credentials(
+    cookies: &Cookies,
+    headers: &HeaderMap,
+    providers: &IdentityProviders,
+) -> Result<(Arc<dyn IdentityProvider>, String)> {
+    let mut provider_name = cookies
+        .get(AUTH_PROVIDER)
+        .map(|c| c.value().to_string())
+        .or_else(|| {
+            headers
+                .get(AUTH_PROVIDER_HEADER)
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_string)
+        });
+
+    let token = if let Some(cookie) = cookies.get(AUTH_TOKEN) {
+        cookie.value().to_string()
+    } else {
+        let header = headers
+            .get(AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .ok_or(Error::AuthFailNoToken)?;
+
+        if let Some(token) = header.strip_prefix("Bearer ") {
+            token.to_string()
+        } else if let Some(encoded) = header.strip_prefix("Basic ") {
+            let decoded = STANDARD.decode(encoded).map_err(|_| Error::AuthFailInvalidToken)?;
+            provider_name.get_or_insert("local".to_string());
+            String::from_utf8(decoded).map_err(|_| Error::AuthFailInvalidToken)?
         } else {
-            Err(Error::DBError {
-                source: "Failed to retrieve newly inserted user".into(),
-            })
+            return Err(Error::AuthFailInvalidToken);
         }
-    } else {
-        Err(Error::DBError {
-            source: "Multiple users found".into(),
+    };
+
+    Ok((providers.get(provider_name.as_deref())?, token))
+}
+
+/// Helper: our session token, from the session cookie or a Bearer header.
+fn extract_session_token(cookies: &Cookies, headers: &HeaderMap) -> Option<String> {
+    cookies
+        .get(SESSION_TOKEN)
+        .map(|c| c.value().to_string())
+        .or_else(|| {
+            headers
+                .get(AUTHORIZATION)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.strip_prefix("Bearer "))
+                .map(str::to_string)
         })
-    }
 }
 
-/// Middleware that *requires* an auth token and sets a valid context.
-/// Returns an error if the token is missing or invalid.
+/// Middleware that *requires* a session token and sets a valid context.
+/// The token is verified locally, the provider is only asked at login.
 pub async fn mw_ctx_resolver(
     State(_mc): State<Arc<SessionController>>,
     cookies: Cookies,
-    Extension(pool): Extension<PgPool>,
+    Extension(tokens): Extension<SessionTokens>,
     mut req: Request<Body>,
     next: Next,
 ) -> Result<Response> {
     println!("->> {:12} - ctx_resolver", "Middleware");
 
-    let auth_token = cookies
-        .get(AUTH_TOKEN)
-        .ok_or(Error::AuthFailNoToken)?
-        .value()
-        .to_string();
+    let token = extract_session_token(&cookies, req.headers()).ok_or(Error::AuthFailNoToken)?;
 
-    let ctx = resolve_ctx_with_auth_token(&pool, &auth_token).await?;
+    let ctx = tokens.verify(&token)?.ctx();
     // Insert the successful context (wrapped in Ok) into extensions.
     req.extensions_mut().insert(Ok::<Ctx, Error>(ctx));
 
     Ok(next.run(req).await)
 }
 
-/// Middleware that attempts to resolve a context if an auth token is present.
+/// Middleware that attempts to resolve a context if a session token is present.
 /// Otherwise, it simply inserts a default “anonymous” context.
 pub async fn mw_optional_ctx_resolver(
     State(_mc): State<Arc<SessionController>>,
     cookies: Cookies,
-    Extension(pool): Extension<PgPool>,
+    Extension(tokens): Extension<SessionTokens>,
     mut req: Request<Body>,
     next: Next,
 ) -> Result<Response> {
     println!("->> {:12} - optional_ctx_resolver", "Middleware");
 
     let default_ctx = Ctx::new("-1".to_string(), "anonymous".to_string(), "".to_string());
-    let ctx_result: Result<Ctx> = if let Some(cookie) = cookies.get(AUTH_TOKEN) {
-        match resolve_ctx_with_auth_token(&pool, cookie.value()).await {
-            Ok(ctx) => Ok(ctx),
+    let ctx_result: Result<Ctx> = if let Some(token) = extract_session_token(&cookies, req.headers()) {
+        match tokens.verify(&token) {
+            Ok(claims) => Ok(claims.ctx()),
             Err(e) => {
                 eprintln!("Authentication error in optional resolver: {:?}", e);
                 Ok(default_ctx)
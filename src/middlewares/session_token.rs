@@ -0,0 +1,159 @@
+use std::collections::HashMap;
+use std::env;
+use std::sync::{ Arc, Mutex };
+use std::time::{ SystemTime, UNIX_EPOCH };
+use dotenvy::dotenv;
+
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use hmac::{ Hmac, Mac };
+use serde::{ Deserialize, Serialize };
+use sha2::{ Digest, Sha256 };
+
+use crate::ctx::Ctx;
+use crate::utils::error::{ Error, Result };
+
+const DEFAULT_ACCESS_TTL_SECS: u64 = 900;
+const DEFAULT_REFRESH_TTL_SECS: u64 = 60 * 60 * 24 * 30;
+// anything shorter than the hmac output is guessable enough to forge tokens
+const MIN_SECRET_BYTES: usize = 32;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// What our session tokens carry, enough to build a Ctx without touching the database.
+/// sid points at the auth_sessions row the token was issued for.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Claims {
+    pub uid: String,
+    pub name: String,
+    pub picture: String,
+    pub sid: String,
+    pub exp: u64,
+}
+
+impl Claims {
+    pub fn ctx(&self) -> Ctx {
+        Ctx::new(self.uid.clone(), self.name.clone(), self.picture.clone())
+    }
+}
+
+/// Issues and verifies first-party session tokens, "<payload>.<signature>" with the
+/// payload being base64url json claims and the signature a HMAC-SHA256 over it.
+/// Logged out sessions are kept in memory until their last access token expires.
+#[derive(Clone, Debug)]
+pub struct SessionTokens {
+    secret: Arc<Vec<u8>>,
+    pub access_ttl: u64,
+    pub refresh_ttl: u64,
+    revoked: Arc<Mutex<HashMap<String, u64>>>,
+}
+
+pub fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+impl SessionTokens {
+    pub fn new(secret: &[u8], access_ttl: u64, refresh_ttl: u64) -> Self {
+        Self {
+            secret: Arc::new(secret.to_vec()),
+            access_ttl,
+            refresh_ttl,
+            revoked: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// SESSION_SECRET (required, at least 32 bytes), SESSION_TOKEN_TTL_SECS (default 900) and
+    /// REFRESH_TOKEN_TTL_SECS (default 30 days).
+    pub fn from_env() -> Result<Self> {
+        dotenv().ok();
+
+        let secret = env::var("SESSION_SECRET").unwrap_or_default();
+        if secret.len() < MIN_SECRET_BYTES {
+            return Err(Error::ConfigError {
+                msg: format!("SESSION_SECRET must be set to at least {} bytes", MIN_SECRET_BYTES),
+            });
+        }
+        let access_ttl = env::var("SESSION_TOKEN_TTL_SECS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(DEFAULT_ACCESS_TTL_SECS);
+        let refresh_ttl = env::var("REFRESH_TOKEN_TTL_SECS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(DEFAULT_REFRESH_TTL_SECS);
+
+        Ok(SessionTokens::new(secret.as_bytes(), access_ttl, refresh_ttl))
+    }
+
+    fn mac(&self) -> HmacSha256 {
+        HmacSha256::new_from_slice(&self.secret).expect("hmac accepts keys of any length")
+    }
+
+    pub fn issue(&self, ctx: &Ctx, sid: &str) -> Result<String> {
+        let claims = Claims {
+            uid: ctx.id(),
+            name: ctx.name(),
+            picture: ctx.picture(),
+            sid: sid.to_string(),
+            exp: now_secs() + self.access_ttl,
+        };
+
+        let payload = serde_json::to_vec(&claims).map_err(|_| Error::AuthFailInvalidToken)?;
+        let payload = URL_SAFE_NO_PAD.encode(payload);
+
+        let mut mac = self.mac();
+        mac.update(payload.as_bytes());
+        let signature = URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes());
+
+        Ok(format!("{}.{}", payload, signature))
+    }
+
+    pub fn verify(&self, token: &str) -> Result<Claims> {
+        let (payload, signature) = token.split_once('.').ok_or(Error::AuthFailInvalidToken)?;
+        let signature = URL_SAFE_NO_PAD.decode(signature).map_err(|_| Error::AuthFailInvalidToken)?;
+
+        let mut mac = self.mac();
+        mac.update(payload.as_bytes());
+        mac.verify_slice(&signature).map_err(|_| Error::AuthFailInvalidToken)?;
+
+        let payload = URL_SAFE_NO_PAD.decode(payload).map_err(|_| Error::AuthFailInvalidToken)?;
+        let claims: Claims = serde_json::from_slice(&payload).map_err(|_| Error::AuthFailInvalidToken)?;
+
+        if claims.exp <= now_secs() || self.is_revoked(&claims.sid) {
+            return Err(Error::AuthFailInvalidToken);
+        }
+
+        Ok(claims)
+    }
+
+    /// Rejects access tokens of a logged out session until they would have expired anyway.
+    pub fn revoke(&self, sid: &str) {
+        let mut revoked = self.revoked.lock().unwrap();
+        let now = now_secs();
+        revoked.retain(|_, until| *until > now);
+        revoked.insert(sid.to_string(), now + self.access_ttl);
+    }
+
+    fn is_revoked(&self, sid: &str) -> bool {
+        self.revoked
+            .lock()
+            .unwrap()
+            .get(sid)
+            .is_some_and(|until| *until > now_secs())
+    }
+
+    /// Refresh tokens are random and only stored hashed.
+    pub fn new_refresh_token() -> String {
+        format!("{}{}", uuid::Uuid::new_v4().simple(), uuid::Uuid::new_v4().simple())
+    }
+
+    pub fn hash_refresh_token(token: &str) -> String {
+        Sha256::digest(token.as_bytes())
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+}
@@ -0,0 +1,110 @@
+use std::collections::HashMap;
+use std::env;
+use std::sync::{ Arc, Mutex };
+use std::time::{ Duration, Instant };
+use dotenvy::dotenv;
+use sha2::{ Digest, Sha256 };
+
+use crate::ctx::Ctx;
+
+const DEFAULT_TTL_SECS: u64 = 300;
+const DEFAULT_CAPACITY: usize = 1024;
+
+// (provider, sha256 of the token) -> (ctx, expires at)
+type Entries = HashMap<(String, [u8; 32]), (Ctx, Instant)>;
+
+/// Resolved contexts keyed by provider and token, so /auth/login only calls out to
+/// the identity provider and the database once per token every ttl. Tokens are only
+/// kept hashed, and the local provider is never cached since its token is a password.
+#[derive(Clone, Debug)]
+pub struct TokenCache {
+    entries: Arc<Mutex<Entries>>,
+    ttl: Duration,
+    capacity: usize,
+}
+
+impl TokenCache {
+    pub fn new(ttl: Duration, capacity: usize) -> Self {
+        Self {
+            entries: Arc::new(Mutex::new(HashMap::new())),
+            ttl,
+            capacity,
+        }
+    }
+
+    /// TOKEN_CACHE_TTL_SECS (default 300) and TOKEN_CACHE_CAPACITY (default 1024),
+    /// a ttl of 0 disables caching.
+    pub fn from_env() -> Self {
+        dotenv().ok();
+
+        let ttl = env::var("TOKEN_CACHE_TTL_SECS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(DEFAULT_TTL_SECS);
+        let capacity = env::var("TOKEN_CACHE_CAPACITY")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(DEFAULT_CAPACITY);
+
+        TokenCache::new(Duration::from_secs(ttl), capacity)
+    }
+
+    fn key(provider: &str, token: &str) -> (String, [u8; 32]) {
+        (provider.to_string(), Sha256::digest(token.as_bytes()).into())
+    }
+
+    pub fn get(&self, provider: &str, token: &str) -> Option<Ctx> {
+        let mut entries = self.entries.lock().unwrap();
+        let key = TokenCache::key(provider, token);
+
+        match entries.get(&key) {
+            Some((ctx, expires)) if *expires > Instant::now() => Some(ctx.clone()),
+            Some(_) => {
+                entries.remove(&key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    pub fn insert(&self, provider: &str, token: &str, ctx: Ctx) {
+        if self.ttl.is_zero() || self.capacity == 0 || provider == "local" {
+            return;
+        }
+
+        let mut entries = self.entries.lock().unwrap();
+        let now = Instant::now();
+
+        if entries.len() >= self.capacity {
+            entries.retain(|_, (_, expires)| *expires > now);
+        }
+
+        // still full after dropping expired entries, evict the one closest to expiring
+        if entries.len() >= self.capacity {
+            let oldest = entries
+                .iter()
+                .min_by_key(|(_, (_, expires))| *expires)
+                .map(|(key, _)| key.clone());
+            if let Some(key) = oldest {
+                entries.remove(&key);
+            }
+        }
+
+        entries.insert(TokenCache::key(provider, token), (ctx, now + self.ttl));
+    }
+
+    pub fn invalidate(&self, provider: &str, token: &str) {
+        self.entries
+            .lock()
+            .unwrap()
+            .remove(&TokenCache::key(provider, token));
+    }
+
+    /// Drops every cached token of a user, e.g. after they log out everywhere.
+    pub fn invalidate_user(&self, user_id: &str) {
+        self.entries
+            .lock()
+            .unwrap()
+            .retain(|_, (ctx, _)| ctx.id() != user_id);
+    }
+}
@@ -1,6 +1,8 @@
 pub mod session;
 pub mod queue;
 pub mod peer;
+pub mod playlist;
+pub mod playlist_format;
 
 // re-export the model module
 pub use session::SessionController;
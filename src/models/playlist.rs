@@ -0,0 +1,241 @@
+use crate::utils::error::{ Error, Result };
+use serde::Serialize;
+use sqlx::{ PgPool, Postgres, Row, Transaction };
+use sqlx::postgres::PgRow;
+
+#[derive(Clone, Debug, Serialize)]
+pub struct PlaylistItem {
+    pub item_id: i32,
+    pub position: i32,
+    pub file_key: String,
+    pub title: String,
+    pub url: String,
+    pub duration_ms: Option<i32>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Playlist {
+    pub playlist_id: i32,
+    pub name: String,
+    pub description: Option<String>,
+    pub item_count: i64,
+    pub created_at: i64,
+    pub updated_at: i64,
+    // only filled in when a single playlist is fetched
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub items: Option<Vec<PlaylistItem>>,
+}
+
+const PLAYLIST_QUERY: &str = "
+    SELECT p.playlist_id, p.name, p.description,
+           (SELECT COUNT(*) FROM playlist_items i
+            JOIN files f ON f.uuid = i.file_uuid AND f.user_id = p.user_id
+            WHERE i.playlist_id = p.playlist_id) AS item_count,
+           EXTRACT(EPOCH FROM p.created_at)::BIGINT AS created_at,
+           EXTRACT(EPOCH FROM p.updated_at)::BIGINT AS updated_at
+    FROM playlists p
+";
+
+fn db_error(e: sqlx::Error) -> Error {
+    Error::DBError { source: e.to_string() }
+}
+
+impl Playlist {
+    fn from_row(row: &PgRow) -> Self {
+        Self {
+            playlist_id: row.get("playlist_id"),
+            name: row.get("name"),
+            description: row.get("description"),
+            item_count: row.get("item_count"),
+            created_at: row.get("created_at"),
+            updated_at: row.get("updated_at"),
+            items: None,
+        }
+    }
+
+    pub async fn list(pool: &PgPool, user_id: i32) -> Result<Vec<Playlist>> {
+        let rows = sqlx::query(&format!("{} WHERE p.user_id = $1 ORDER BY p.updated_at DESC", PLAYLIST_QUERY))
+            .bind(user_id)
+            .fetch_all(pool)
+            .await
+            .map_err(db_error)?;
+
+        Ok(rows.iter().map(Playlist::from_row).collect())
+    }
+
+    pub async fn get(pool: &PgPool, user_id: i32, playlist_id: i32) -> Result<Playlist> {
+        let row = sqlx::query(&format!("{} WHERE p.playlist_id = $1 AND p.user_id = $2", PLAYLIST_QUERY))
+            .bind(playlist_id)
+            .bind(user_id)
+            .fetch_optional(pool)
+            .await
+            .map_err(db_error)?
+            .ok_or(Error::PlaylistNotFound { id: playlist_id })?;
+
+        let mut playlist = Playlist::from_row(&row);
+        playlist.items = Some(Playlist::items(pool, playlist_id).await?);
+        Ok(playlist)
+    }
+
+    pub async fn items(pool: &PgPool, playlist_id: i32) -> Result<Vec<PlaylistItem>> {
+        let rows = sqlx::query(
+            "SELECT i.item_id, i.position, i.file_uuid, i.title, f.url, f.duration_ms
+             FROM playlist_items i
+             JOIN playlists p ON p.playlist_id = i.playlist_id
+             JOIN files f ON f.uuid = i.file_uuid AND f.user_id = p.user_id
+             WHERE i.playlist_id = $1
+             ORDER BY i.position"
+        )
+        .bind(playlist_id)
+        .fetch_all(pool)
+        .await
+        .map_err(db_error)?;
+
+        Ok(rows.iter().map(|row| PlaylistItem {
+            item_id: row.get("item_id"),
+            position: row.get("position"),
+            file_key: row.get("file_uuid"),
+            title: row.get("title"),
+            url: row.get("url"),
+            duration_ms: row.get("duration_ms"),
+        }).collect())
+    }
+
+    // returns the new id and the keys that were skipped because the user has no such file
+    pub async fn create(pool: &PgPool, user_id: i32, name: &str, description: Option<&str>, keys: &[String]) -> Result<(i32, Vec<String>)> {
+        let mut tx = pool.begin().await.map_err(db_error)?;
+
+        let playlist_id: i32 = sqlx::query(
+            "INSERT INTO playlists (user_id, name, description) VALUES ($1, $2, $3) RETURNING playlist_id"
+        )
+        .bind(user_id)
+        .bind(name)
+        .bind(description)
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(db_error)?
+        .get("playlist_id");
+
+        let skipped = Playlist::set_items(&mut tx, user_id, playlist_id, keys).await?;
+        tx.commit().await.map_err(db_error)?;
+        Ok((playlist_id, skipped))
+    }
+
+    // fields left as None stay as they are, Some(None) clears the description,
+    // items replace the whole list when given. returns the skipped keys like create
+    pub async fn update(
+        pool: &PgPool,
+        user_id: i32,
+        playlist_id: i32,
+        name: Option<&str>,
+        description: Option<Option<&str>>,
+        keys: Option<&[String]>,
+    ) -> Result<Vec<String>> {
+        let mut tx = pool.begin().await.map_err(db_error)?;
+
+        let updated = sqlx::query(
+            "UPDATE playlists
+             SET name = COALESCE($3, name),
+                 description = CASE WHEN $5 THEN $4 ELSE description END,
+                 updated_at = NOW()
+             WHERE playlist_id = $1 AND user_id = $2"
+        )
+        .bind(playlist_id)
+        .bind(user_id)
+        .bind(name)
+        .bind(description.flatten())
+        .bind(description.is_some())
+        .execute(&mut *tx)
+        .await
+        .map_err(db_error)?;
+
+        if updated.rows_affected() == 0 {
+            return Err(Error::PlaylistNotFound { id: playlist_id });
+        }
+
+        let mut skipped = Vec::new();
+        if let Some(keys) = keys {
+            sqlx::query("DELETE FROM playlist_items WHERE playlist_id = $1")
+                .bind(playlist_id)
+                .execute(&mut *tx)
+                .await
+                .map_err(db_error)?;
+            skipped = Playlist::set_items(&mut tx, user_id, playlist_id, keys).await?;
+        }
+
+        tx.commit().await.map_err(db_error)?;
+        Ok(skipped)
+    }
+
+    pub async fn delete(pool: &PgPool, user_id: i32, playlist_id: i32) -> Result<()> {
+        let deleted = sqlx::query("DELETE FROM playlists WHERE playlist_id = $1 AND user_id = $2")
+            .bind(playlist_id)
+            .bind(user_id)
+            .execute(pool)
+            .await
+            .map_err(db_error)?;
+
+        if deleted.rows_affected() == 0 {
+            return Err(Error::PlaylistNotFound { id: playlist_id });
+        }
+        Ok(())
+    }
+
+    // puts one file at a given position, used when imported entries arrive one by one
+    pub async fn add_item(pool: &PgPool, user_id: i32, playlist_id: i32, position: i32, key: &str) -> Result<()> {
+        let added = sqlx::query(
+            "INSERT INTO playlist_items (playlist_id, position, file_uuid, title)
+             SELECT p.playlist_id, $2, f.uuid, COALESCE(f.name, '')
+             FROM playlists p
+             JOIN files f ON f.uuid = $3 AND f.user_id = p.user_id
+             WHERE p.playlist_id = $1 AND p.user_id = $4"
+        )
+        .bind(playlist_id)
+        .bind(position)
+        .bind(key)
+        .bind(user_id)
+        .execute(pool)
+        .await
+        .map_err(db_error)?;
+
+        if added.rows_affected() == 0 {
+            return Err(Error::PlaylistNotFound { id: playlist_id });
+        }
+
+        sqlx::query("UPDATE playlists SET updated_at = NOW() WHERE playlist_id = $1")
+            .bind(playlist_id)
+            .execute(pool)
+            .await
+            .map_err(db_error)?;
+        Ok(())
+    }
+
+    // appends the keys in order, keys that are not in the user's library are skipped and returned
+    async fn set_items(tx: &mut Transaction<'_, Postgres>, user_id: i32, playlist_id: i32, keys: &[String]) -> Result<Vec<String>> {
+        sqlx::query(
+            "INSERT INTO playlist_items (playlist_id, position, file_uuid, title)
+             SELECT $1, k.ord, f.uuid, COALESCE(f.name, '')
+             FROM UNNEST($2::TEXT[]) WITH ORDINALITY AS k(key, ord)
+             JOIN files f ON f.uuid = k.key AND f.user_id = $3
+             ORDER BY k.ord"
+        )
+        .bind(playlist_id)
+        .bind(keys)
+        .bind(user_id)
+        .execute(&mut **tx)
+        .await
+        .map_err(db_error)?;
+
+        let skipped = sqlx::query(
+            "SELECT k.key FROM UNNEST($1::TEXT[]) AS k(key)
+             WHERE NOT EXISTS (SELECT 1 FROM files f WHERE f.uuid = k.key AND f.user_id = $2)"
+        )
+        .bind(keys)
+        .bind(user_id)
+        .fetch_all(&mut **tx)
+        .await
+        .map_err(db_error)?;
+
+        Ok(skipped.iter().map(|row| row.get("key")).collect())
+    }
+}
@@ -0,0 +1,329 @@
+use crate::utils::error::{ Error, Result };
+use crate::models::playlist::Playlist;
+use serde::{ Deserialize, Serialize };
+use serde_json::json;
+use quick_xml::escape::escape;
+use quick_xml::events::Event;
+use quick_xml::Reader;
+
+/// Playlist file formats for import and export.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PlaylistFormat {
+    M3u,
+    Xspf,
+    Json,
+}
+
+/// One track of an imported playlist, before it is matched to a file.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ImportEntry {
+    pub title: String,
+    pub url: Option<String>,
+    pub duration_ms: Option<i32>,
+}
+
+// the json format is what export writes: { "name": ..., "tracks": [ImportEntry] }
+#[derive(Debug, Deserialize)]
+struct JsonPlaylist {
+    name: Option<String>,
+    tracks: Vec<ImportEntry>,
+}
+
+impl PlaylistFormat {
+    pub fn from_name(name: &str) -> Result<Self> {
+        match name.to_lowercase().as_str() {
+            "m3u" | "m3u8" => Ok(PlaylistFormat::M3u),
+            "xspf" => Ok(PlaylistFormat::Xspf),
+            "json" => Ok(PlaylistFormat::Json),
+            other => Err(Error::UnsupportedFormat { format: other.to_string() }),
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            PlaylistFormat::M3u => "audio/x-mpegurl",
+            PlaylistFormat::Xspf => "application/xspf+xml",
+            PlaylistFormat::Json => "application/json",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            PlaylistFormat::M3u => "m3u",
+            PlaylistFormat::Xspf => "xspf",
+            PlaylistFormat::Json => "json",
+        }
+    }
+
+    // tracks point at their source url, that is what other tools can resolve
+    pub fn render(&self, playlist: &Playlist) -> String {
+        let items = playlist.items.clone().unwrap_or_default();
+
+        match self {
+            PlaylistFormat::M3u => {
+                let mut out = String::from("#EXTM3U\n");
+                out.push_str(&format!("#PLAYLIST:{}\n", m3u_line(&playlist.name)));
+                for item in items {
+                    let seconds = item.duration_ms.map(|d| d / 1000).unwrap_or(-1);
+                    out.push_str(&format!("#EXTINF:{},{}\n{}\n", seconds, m3u_line(&item.title), m3u_line(&item.url)));
+                }
+                out
+            }
+            PlaylistFormat::Xspf => {
+                let mut out = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+                out.push_str("<playlist version=\"1\" xmlns=\"http://xspf.org/ns/0/\">\n");
+                out.push_str(&format!("  <title>{}</title>\n  <trackList>\n", escape(&playlist.name)));
+                for item in items {
+                    out.push_str("    <track>\n");
+                    out.push_str(&format!("      <location>{}</location>\n", escape(&item.url)));
+                    out.push_str(&format!("      <title>{}</title>\n", escape(&item.title)));
+                    if let Some(duration) = item.duration_ms {
+                        out.push_str(&format!("      <duration>{}</duration>\n", duration));
+                    }
+                    out.push_str("    </track>\n");
+                }
+                out.push_str("  </trackList>\n</playlist>\n");
+                out
+            }
+            PlaylistFormat::Json => {
+                let tracks: Vec<ImportEntry> = items.into_iter().map(|item| ImportEntry {
+                    title: item.title,
+                    url: Some(item.url),
+                    duration_ms: item.duration_ms,
+                }).collect();
+
+                serde_json::to_string_pretty(&json!({
+                    "name": playlist.name,
+                    "description": playlist.description,
+                    "tracks": tracks,
+                })).unwrap_or_default()
+            }
+        }
+    }
+
+    /// Returns the playlist name if the file has one, and its tracks in order.
+    pub fn parse(&self, content: &str) -> Result<(Option<String>, Vec<ImportEntry>)> {
+        match self {
+            PlaylistFormat::M3u => Ok(parse_m3u(content)),
+            PlaylistFormat::Xspf => parse_xspf(content),
+            PlaylistFormat::Json => {
+                let playlist: JsonPlaylist = serde_json::from_str(content)
+                    .map_err(|e| Error::PlayListParseErr { msg: e.to_string() })?;
+                Ok((playlist.name, playlist.tracks))
+            }
+        }
+    }
+}
+
+fn parse_m3u(content: &str) -> (Option<String>, Vec<ImportEntry>) {
+    let mut name = None;
+    let mut entries = Vec::new();
+    let mut pending = ImportEntry::default();
+
+    for line in content.lines().map(str::trim).filter(|line| !line.is_empty()) {
+        if let Some(info) = line.strip_prefix("#EXTINF:") {
+            // #EXTINF:<seconds>,<title>
+            let (seconds, title) = info.split_once(',').unwrap_or((info, ""));
+            pending.duration_ms = seconds.trim().parse::<i32>().ok().filter(|s| *s >= 0).and_then(|s| s.checked_mul(1000));
+            pending.title = title.trim().to_string();
+        } else if let Some(title) = line.strip_prefix("#PLAYLIST:") {
+            name = Some(title.trim().to_string());
+        } else if !line.starts_with('#') {
+            let mut entry = std::mem::take(&mut pending);
+            if entry.title.is_empty() {
+                entry.title = line.rsplit('/').next().unwrap_or(line).to_string();
+            }
+            entry.url = Some(line.to_string());
+            entries.push(entry);
+        }
+    }
+
+    (name, entries)
+}
+
+// a line break in a title or url would start a new entry
+fn m3u_line(text: &str) -> String {
+    text.replace(['\r', '\n'], " ")
+}
+
+fn xml_error(e: impl std::fmt::Display) -> Error {
+    Error::PlayListParseErr { msg: e.to_string() }
+}
+
+// tags are matched by local name, so namespaced and attributed elements read the same
+fn parse_xspf(content: &str) -> Result<(Option<String>, Vec<ImportEntry>)> {
+    let mut reader = Reader::from_str(content);
+    let mut path: Vec<Vec<u8>> = Vec::new();
+    let mut text = String::new();
+
+    let mut name = None;
+    let mut entries = Vec::new();
+    let mut track: Option<ImportEntry> = None;
+
+    loop {
+        match reader.read_event().map_err(xml_error)? {
+            Event::Start(e) => {
+                let tag = e.local_name().as_ref().to_vec();
+                if tag == b"track" {
+                    track = Some(ImportEntry::default());
+                }
+                path.push(tag);
+                text.clear();
+            }
+            Event::Text(t) => text.push_str(&t.unescape().map_err(xml_error)?),
+            Event::CData(c) => text.push_str(&String::from_utf8_lossy(&c.into_inner())),
+            Event::End(_) => {
+                let tag = path.pop().unwrap_or_default();
+                let value = text.trim().to_string();
+                text.clear();
+
+                match (tag.as_slice(), path.last().map(Vec::as_slice)) {
+                    (b"track", _) => {
+                        if let Some(mut entry) = track.take() {
+                            if entry.url.is_none() && entry.title.is_empty() {
+                                continue;
+                            }
+                            if entry.title.is_empty() {
+                                entry.title = entry.url.clone().unwrap_or_default();
+                            }
+                            entries.push(entry);
+                        }
+                    }
+                    // a track may list several locations, the first one is used
+                    (b"location", Some(b"track")) => {
+                        if let Some(entry) = track.as_mut().filter(|entry| entry.url.is_none()) {
+                            entry.url = Some(value);
+                        }
+                    }
+                    (b"title", Some(b"track")) => {
+                        if let Some(entry) = track.as_mut() {
+                            entry.title = value;
+                        }
+                    }
+                    (b"duration", Some(b"track")) => {
+                        if let Some(entry) = track.as_mut() {
+                            entry.duration_ms = value.parse().ok();
+                        }
+                    }
+                    (b"title", Some(b"playlist")) => name = Some(value),
+                    _ => (),
+                }
+            }
+            Event::Eof => break,
+            _ => (),
+        }
+    }
+
+    Ok((name, entries))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::playlist::PlaylistItem;
+
+    fn playlist() -> Playlist {
+        let item = |item_id: i32, title: &str, url: &str, duration_ms: Option<i32>| PlaylistItem {
+            item_id,
+            position: item_id,
+            file_key: format!("key-{}", item_id),
+            title: title.to_string(),
+            url: url.to_string(),
+            duration_ms,
+        };
+
+        Playlist {
+            playlist_id: 1,
+            name: "Road <trip> & more".to_string(),
+            description: None,
+            item_count: 3,
+            created_at: 0,
+            updated_at: 0,
+            items: Some(vec![
+                item(1, "Daft Punk - One More Time", "https://www.youtube.com/watch?v=FGBhQbmPwH8", Some(320000)),
+                item(2, "Tom & Jerry's \"theme\"", "https://example.com/a?b=1&c=2", None),
+                item(3, "broken\ntitle, with comma", "https://example.com/b", Some(5000)),
+            ]),
+        }
+    }
+
+    fn round_trip(format: PlaylistFormat) -> (Option<String>, Vec<ImportEntry>) {
+        format.parse(&format.render(&playlist())).unwrap()
+    }
+
+    #[test]
+    fn json_round_trip() {
+        let (name, entries) = round_trip(PlaylistFormat::Json);
+
+        assert_eq!(name.as_deref(), Some("Road <trip> & more"));
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[1].title, "Tom & Jerry's \"theme\"");
+        assert_eq!(entries[1].url.as_deref(), Some("https://example.com/a?b=1&c=2"));
+        assert_eq!(entries[0].duration_ms, Some(320000));
+        assert_eq!(entries[1].duration_ms, None);
+    }
+
+    #[test]
+    fn xspf_round_trip() {
+        let (name, entries) = round_trip(PlaylistFormat::Xspf);
+
+        assert_eq!(name.as_deref(), Some("Road <trip> & more"));
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[1].title, "Tom & Jerry's \"theme\"");
+        assert_eq!(entries[1].url.as_deref(), Some("https://example.com/a?b=1&c=2"));
+        assert_eq!(entries[2].duration_ms, Some(5000));
+    }
+
+    #[test]
+    fn m3u_round_trip() {
+        let (name, entries) = round_trip(PlaylistFormat::M3u);
+
+        assert_eq!(name.as_deref(), Some("Road <trip> & more"));
+        // the line break in the third title must not split it into two entries
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[0].title, "Daft Punk - One More Time");
+        assert_eq!(entries[0].duration_ms, Some(320000));
+        assert_eq!(entries[1].duration_ms, None);
+        assert_eq!(entries[2].title, "broken title, with comma");
+        assert_eq!(entries[2].url.as_deref(), Some("https://example.com/b"));
+    }
+
+    #[test]
+    fn m3u_duration_overflow_is_dropped() {
+        let (_, entries) = PlaylistFormat::M3u.parse("#EXTM3U\n#EXTINF:2147484,long\nhttps://example.com/x\n").unwrap();
+
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].duration_ms, None);
+    }
+
+    #[test]
+    fn xspf_with_attributes_entities_and_cdata() {
+        let content = r#"<?xml version="1.0" encoding="UTF-8"?>
+            <playlist version="1" xmlns="http://xspf.org/ns/0/">
+              <title>Mix &#38; match</title>
+              <trackList xmlns:x="urn:x">
+                <track xml:base="https://example.com/">
+                  <location>https://example.com/a?x=1&#x26;y=2</location>
+                  <title><![CDATA[Rock & <Roll>]]></title>
+                  <duration>1000</duration>
+                </track>
+                <track><title>no location</title></track>
+              </trackList>
+            </playlist>"#;
+
+        let (name, entries) = PlaylistFormat::Xspf.parse(content).unwrap();
+
+        assert_eq!(name.as_deref(), Some("Mix & match"));
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].url.as_deref(), Some("https://example.com/a?x=1&y=2"));
+        assert_eq!(entries[0].title, "Rock & <Roll>");
+        assert_eq!(entries[0].duration_ms, Some(1000));
+        assert_eq!(entries[1].url, None);
+        assert_eq!(entries[1].title, "no location");
+    }
+
+    #[test]
+    fn malformed_xspf_is_a_parse_error() {
+        assert!(PlaylistFormat::Xspf.parse("<playlist><trackList><track></playlist>").is_err());
+    }
+}
@@ -1,5 +1,5 @@
-use std::collections::HashMap;
-use crate::utils::error::{ Result, Error };
+use serde::Serialize;
+use std::time::{ SystemTime, UNIX_EPOCH };
 
 pub enum QueueAction {
     Next(String),
@@ -8,10 +8,27 @@ pub enum QueueAction {
     NotFound,
 }
 
+/// One entry of a session queue. The item id stays the same while other
+/// entries move around, so clients address items by id instead of position.
+#[derive(Clone, Debug, Serialize)]
+pub struct QueueItem {
+    pub item_id: u64,
+    pub file_key: String,
+    pub title: String,
+    // user id of whoever queued the item
+    pub added_by: String,
+    // unix seconds
+    pub added_at: i64,
+    // milliseconds, unknown for files without metadata
+    pub duration: Option<i32>,
+}
+
 #[derive(Clone, Debug)]
 pub struct PlayQueue {
-    queue: Vec<Vec<String>>,
+    queue: Vec<QueueItem>,
     curr_index: usize,
+    // ids are never reused within a session
+    next_item_id: u64,
 }
 
 impl PlayQueue {
@@ -19,30 +36,58 @@ impl PlayQueue {
         Self {
             queue: Vec::new(),
             curr_index: 0,
+            next_item_id: 1,
         }
     }
 
-    pub fn get_all(&self) -> Vec<Vec<String>> {
+    pub fn get_all(&self) -> Vec<QueueItem> {
         self.queue.clone()
     }
 
+    // returns the id given to the new item along with what the session should do
     pub fn add(
         &mut self, 
         key: String, 
         title: String, 
-    ) -> QueueAction {
-        let item = vec![key.clone(), title];
-
-        self.queue.push(item);
+        added_by: String,
+        duration: Option<i32>,
+    ) -> (u64, QueueAction) {
+        let item_id = self.next_item_id;
+        self.next_item_id += 1;
+
+        let added_at = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs() as i64)
+            .unwrap_or(0);
+
+        self.queue.push(QueueItem {
+            item_id,
+            file_key: key,
+            title,
+            added_by,
+            added_at,
+            duration,
+        });
         // item added is the only item in the queue, return the key
         if self.queue.len() == 1 {
-            return QueueAction::Next(self.next())
+            return (item_id, QueueAction::Next(self.next()))
         }
 
-        QueueAction::Pass
+        (item_id, QueueAction::Pass)
     }
 
-    pub fn remove_by_id(&mut self, index: usize) -> QueueAction {
+    fn index_of(&self, item_id: u64) -> Option<usize> {
+        self.queue.iter().position(|item| item.item_id == item_id)
+    }
+
+    pub fn remove_by_item_id(&mut self, item_id: u64) -> QueueAction {
+        match self.index_of(item_id) {
+            Some(index) => self.remove_at(index),
+            None => QueueAction::NotFound,
+        }
+    }
+
+    fn remove_at(&mut self, index: usize) -> QueueAction {
 
         if self.queue.is_empty() {
             return QueueAction::Pass;
@@ -69,7 +114,7 @@ impl PlayQueue {
                         }
                         // if the queue is not empty, “play the same index” which now holds the next track
                         println!("remove same index, curr_index: {}", self.curr_index);
-                        return QueueAction::Next(self.queue[self.curr_index][0].clone());
+                        return QueueAction::Next(self.queue[self.curr_index].file_key.clone());
                     }
                 }
 
@@ -81,12 +126,12 @@ impl PlayQueue {
         }
     }
 
-    pub fn remove_by_key(&mut self, key: String) -> QueueAction {
+    // removes every item of the key that added_by queued, items others queued stay
+    pub fn remove_by_key(&mut self, key: String, added_by: &str) -> QueueAction {
 
-        // remove all items with the same key
         let mut indexes = Vec::new();
         for (i, item) in self.queue.iter().enumerate() {
-            if item[0] == key {
+            if item.file_key == key && item.added_by == added_by {
                 indexes.push(i);
             }
         }
@@ -108,7 +153,7 @@ impl PlayQueue {
                 return QueueAction::Stop;
             }
             
-            return QueueAction::Next(self.queue[self.curr_index][0].clone());
+            return QueueAction::Next(self.queue[self.curr_index].file_key.clone());
 
         } else {
             while !indexes.is_empty() {
@@ -125,7 +170,30 @@ impl PlayQueue {
         }
     }
 
-    pub fn reorder(&mut self, old_index: usize, new_index: usize) -> QueueAction {
+    // moves the item in front of before_item_id, or to the end when that is None
+    pub fn move_item(&mut self, item_id: u64, before_item_id: Option<u64>) -> QueueAction {
+        let old_index = match self.index_of(item_id) {
+            Some(index) => index,
+            None => return QueueAction::NotFound,
+        };
+        let target = match before_item_id {
+            Some(before) => match self.index_of(before) {
+                Some(index) => index,
+                None => return QueueAction::NotFound,
+            },
+            None => self.queue.len(),
+        };
+
+        // reorder inserts after the item is taken out, so later targets shift by one
+        let new_index = if target > old_index { target - 1 } else { target };
+        if new_index == old_index {
+            return QueueAction::Pass;
+        }
+
+        self.reorder(old_index, new_index)
+    }
+
+    fn reorder(&mut self, old_index: usize, new_index: usize) -> QueueAction {
         if old_index >= self.queue.len() || new_index > self.queue.len() {
             return QueueAction::NotFound;
         }
@@ -182,13 +250,28 @@ impl PlayQueue {
             self.curr_index -= 1;
         }
 
-        self.queue[self.curr_index][0].clone()
+        self.queue[self.curr_index].file_key.clone()
+    }
+
+    // key of the item next() would move to, without moving
+    pub fn peek_next(&self) -> String {
+        if self.queue.is_empty() {
+            return String::from("");
+        }
+
+        let index = (self.curr_index + 1) % self.queue.len();
+        self.queue[index].file_key.clone()
     }
 
     pub fn get_id(&self) -> String {
         return self.curr_index.to_string();
     }
 
+    // the index alone is ambiguous once items move, clients match on the item id
+    pub fn current_item_id(&self) -> Option<u64> {
+        self.queue.get(self.curr_index).map(|item| item.item_id)
+    }
+
     pub fn prev(&mut self) -> String {
         if self.queue.len() == 0 {
             return String::from("");
@@ -204,11 +287,11 @@ impl PlayQueue {
             self.curr_index -= 1;
         }
 
-        self.queue[self.curr_index][0].clone()
+        self.queue[self.curr_index].file_key.clone()
     }
 
     pub fn has_key(&self, key: String) -> bool {
-        self.queue.iter().any(|x| x[0] == key)
+        self.queue.iter().any(|x| x.file_key == key)
     }
 
     pub fn get_title(&self, index: usize) -> String {
@@ -216,10 +299,114 @@ impl PlayQueue {
             return "".to_string();
         }
 
-        self.queue[index][1].clone()
+        self.queue[index].title.clone()
     }
     
     pub fn is_empty(&self) -> bool {
         self.queue.is_empty()
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a, b, c, d with item ids 1 to 4, current item is the one at `current`
+    fn queue(current: usize) -> PlayQueue {
+        let mut queue = PlayQueue::new();
+        for key in ["a", "b", "c", "d"] {
+            queue.add(key.to_string(), key.to_uppercase(), "1".to_string(), None);
+        }
+        for _ in 0..current {
+            queue.next();
+        }
+        queue
+    }
+
+    fn keys(queue: &PlayQueue) -> Vec<String> {
+        queue.get_all().into_iter().map(|item| item.file_key).collect()
+    }
+
+    #[test]
+    fn move_item_keeps_current_item_when_others_move() {
+        let mut q = queue(2);
+        assert!(matches!(q.move_item(1, None), QueueAction::Pass));
+        assert_eq!(keys(&q), ["b", "c", "d", "a"]);
+        assert_eq!(q.get_id(), "1");
+        assert_eq!(q.current_item_id(), Some(3));
+
+        let mut q = queue(1);
+        assert!(matches!(q.move_item(4, Some(1)), QueueAction::Pass));
+        assert_eq!(keys(&q), ["d", "a", "b", "c"]);
+        assert_eq!(q.get_id(), "2");
+        assert_eq!(q.current_item_id(), Some(2));
+    }
+
+    #[test]
+    fn move_item_follows_the_current_item() {
+        let mut q = queue(2);
+        assert!(matches!(q.move_item(3, Some(1)), QueueAction::Pass));
+        assert_eq!(keys(&q), ["c", "a", "b", "d"]);
+        assert_eq!(q.get_id(), "0");
+        assert_eq!(q.current_item_id(), Some(3));
+
+        assert!(matches!(q.move_item(3, None), QueueAction::Pass));
+        assert_eq!(keys(&q), ["a", "b", "d", "c"]);
+        assert_eq!(q.current_item_id(), Some(3));
+    }
+
+    #[test]
+    fn move_item_in_place_or_unknown() {
+        let mut q = queue(0);
+        assert!(matches!(q.move_item(2, Some(3)), QueueAction::Pass));
+        assert!(matches!(q.move_item(4, None), QueueAction::Pass));
+        assert_eq!(keys(&q), ["a", "b", "c", "d"]);
+
+        assert!(matches!(q.move_item(9, None), QueueAction::NotFound));
+        assert!(matches!(q.move_item(1, Some(9)), QueueAction::NotFound));
+        assert_eq!(keys(&q), ["a", "b", "c", "d"]);
+    }
+
+    #[test]
+    fn remove_by_item_id_shifts_current_index() {
+        let mut q = queue(2);
+        assert!(matches!(q.remove_by_item_id(1), QueueAction::Pass));
+        assert_eq!(q.get_id(), "1");
+        assert_eq!(q.current_item_id(), Some(3));
+
+        assert!(matches!(q.remove_by_item_id(4), QueueAction::Pass));
+        assert_eq!(q.current_item_id(), Some(3));
+        assert!(matches!(q.remove_by_item_id(9), QueueAction::NotFound));
+    }
+
+    #[test]
+    fn remove_by_key_only_takes_the_users_items() {
+        let mut q = queue(0);
+        q.add("c".to_string(), "C".to_string(), "2".to_string(), None);
+
+        assert!(matches!(q.remove_by_key("c".to_string(), "1"), QueueAction::Pass));
+        assert_eq!(keys(&q), ["a", "b", "d", "c"]);
+        assert_eq!(q.get_all()[3].added_by, "2");
+
+        assert!(matches!(q.remove_by_key("a".to_string(), "2"), QueueAction::Pass));
+        assert!(matches!(q.remove_by_key("a".to_string(), "1"), QueueAction::Next(key) if key == "b"));
+        assert_eq!(keys(&q), ["b", "d", "c"]);
+    }
+
+    #[test]
+    fn remove_current_item_plays_the_next_one() {
+        let mut q = queue(1);
+        assert!(matches!(q.remove_by_item_id(2), QueueAction::Next(key) if key == "c"));
+        assert_eq!(q.current_item_id(), Some(3));
+
+        // removing the last item falls back to the one before it
+        let mut q = queue(3);
+        assert!(matches!(q.remove_by_item_id(4), QueueAction::Next(key) if key == "c"));
+        assert_eq!(q.current_item_id(), Some(3));
+
+        let mut q = PlayQueue::new();
+        let (item_id, _) = q.add("a".to_string(), "A".to_string(), "1".to_string(), None);
+        assert!(matches!(q.remove_by_item_id(item_id), QueueAction::Stop));
+        assert_eq!(q.current_item_id(), None);
+    }
+}
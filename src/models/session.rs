@@ -3,12 +3,15 @@ use crate::media::broadcaster::{
     BroadcasterHandle,
     BroadcasterCommand,
     BroadcasterEvent,
+    NowPlaying,
+    MAX_CROSSFADE_MS,
 };
 
 use serde::{Deserialize, Serialize};
+use serde_json::json;
 use std::sync::Arc;
 use std::path::Path;
-use tokio::sync::Mutex;
+use tokio::sync::{ Mutex, MutexGuard };
 use tokio::sync::Notify;
 use tokio::fs;
 use tokio::sync::oneshot;
@@ -41,9 +44,10 @@ use std::io::Write;
 use tokio::time::Instant;
 
 use crate::media::file_manager::{ FileManager, FMDownloadParams };
-use crate::models::queue::PlayQueue;
+use crate::models::queue::{ PlayQueue, QueueItem };
 use crate::models::queue::QueueAction::{ Next, Stop, Pass, NotFound };
 use crate::media::broadcaster::Broadcaster;
+use crate::storage::{ self, StorageBackend };
 use crate::models::peer::{
     PeerConnection,
     Listener,
@@ -74,6 +78,8 @@ pub struct Session {
     pub broadcaster: BroadcasterHandle,
     pub queue: Arc<Mutex<PlayQueue>>,
     pub update: Arc<Mutex<broadcast::Sender<String>>>,
+    pub paused: Arc<Mutex<bool>>,
+    pub crossfade_ms: Arc<Mutex<u64>>,
 } 
 
 impl Session {
@@ -82,6 +88,7 @@ impl Session {
         owner: User,
         broadcaster_handle: BroadcasterHandle,
         peer_connections: Arc<Mutex<HashMap<String, PeerConnection>>>,
+        queue: Arc<Mutex<PlayQueue>>,
     ) -> Result<Self> {
 
         let session = Self {
@@ -90,8 +97,10 @@ impl Session {
             start_time: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64,
             peer_connections, 
             broadcaster: broadcaster_handle,
-            queue: Arc::new(Mutex::new(PlayQueue::new())),
+            queue,
             update: Arc::new(Mutex::new(broadcast::channel(100).0)),
+            paused: Arc::new(Mutex::new(false)),
+            crossfade_ms: Arc::new(Mutex::new(0)),
         };
 
         session.autoplay_loop().await?;
@@ -170,81 +179,106 @@ impl Session {
         Ok(peers)
     }
 
-    pub async fn get_queue_position(&self) -> Result<String> {
+    // index of the current item along with its item id
+    pub async fn get_queue_position(&self) -> Result<(String, Option<u64>)> {
         let queue = self.queue.lock().await;
-        Ok(queue.get_id())
+        Ok((queue.get_id(), queue.current_item_id()))
     }
 
-    pub async fn get_queue(&self) -> Result<Vec<Vec<String>>> {
+    pub async fn get_queue(&self) -> Result<Vec<QueueItem>> {
         let queue = self.queue.lock().await;
         Ok(queue.get_all())
     }
 
     // queue change opreations pass in a function call back
-    pub async fn add_to_queue(&self, key: String, title: String) -> Result<()> {
+    pub async fn add_to_queue(&self, key: String, title: String, added_by: String, duration: Option<i32>) -> Result<u64> {
         let mut queue = self.queue.lock().await;
-        match queue.add(key, title) {
+        let next_before = queue.peek_next();
+        let (item_id, action) = queue.add(key, title, added_by, duration);
+        match action {
             Next(key) => {
                 self.play(key).await?;
-                self.ping(queue.get_id()).await?;
+                self.ping(position_event(&queue)).await?;
             },
-            Pass => self.ping(queue.get_id()).await?,
-            _ => self.ping(queue.get_id()).await?,
+            Pass => self.ping(position_event(&queue)).await?,
+            _ => self.ping(position_event(&queue)).await?,
         }
-        Ok(())
+        self.discard_prefetch_if_changed(queue, next_before).await?;
+        Ok(item_id)
     }
 
-    pub async fn remove_from_queue(&self, index: usize) -> Result<()> {
+    pub async fn remove_from_queue(&self, item_id: u64) -> Result<()> {
         let mut queue = self.queue.lock().await;
-        match queue.remove_by_id(index) {
+        let next_before = queue.peek_next();
+        match queue.remove_by_item_id(item_id) {
             Next(key) => {
                 self.play(key).await?;
-                self.ping(queue.get_id()).await?;
+                self.ping(position_event(&queue)).await?;
             },
             Stop => {
                 self.clean_active_file().await?;
-                self.ping(queue.get_id()).await?;
+                self.ping(position_event(&queue)).await?;
             },
             NotFound => {
-                return Err(Error::QueueError { msg: "Index not found".to_string() });
+                return Err(Error::QueueError { msg: "Item not found".to_string() });
             },
             Pass => { 
-                self.ping(queue.get_id()).await?;
+                self.ping(position_event(&queue)).await?;
             }
         }
+        self.discard_prefetch_if_changed(queue, next_before).await?;
         Ok(())
     }
 
-    pub async fn remove_key_from_queue(&self, key: String) -> Result<()> {
+    // only the items added_by queued, a shared or public key may also be queued by others
+    pub async fn remove_key_from_queue(&self, key: String, added_by: &str) -> Result<()> {
         let mut queue = self.queue.lock().await;
-        match queue.remove_by_key(key) {
+        let next_before = queue.peek_next();
+        match queue.remove_by_key(key, added_by) {
             Next(key) => {
                 self.play(key).await?;
-                self.ping(queue.get_id()).await?;
+                self.ping(position_event(&queue)).await?;
             },
             Stop => {
                 self.clean_active_file().await?;
-                self.ping(queue.get_id()).await?;
+                self.ping(position_event(&queue)).await?;
             },
             NotFound => {
                 return Err(Error::QueueError { msg: "Key not found".to_string() });
             },
             Pass => { 
-                self.ping(queue.get_id()).await?;
+                self.ping(position_event(&queue)).await?;
             }
         }
+        self.discard_prefetch_if_changed(queue, next_before).await?;
         Ok(())
     }
 
-    pub async fn reorder_queue(&self, old_index: usize, new_index: usize) -> Result<()> {
+    pub async fn reorder_queue(&self, item_id: u64, before_item_id: Option<u64>) -> Result<()> {
         let mut queue = self.queue.lock().await;
-        match queue.reorder(old_index, new_index) {
+        let next_before = queue.peek_next();
+        match queue.move_item(item_id, before_item_id) {
             Next(key) => {
-                self.ping(queue.get_id()).await?;
+                self.ping(position_event(&queue)).await?;
                 self.play(key).await?;
             },
-            Pass => self.ping(queue.get_id()).await?,
-            _ => self.ping(queue.get_id()).await?,
+            NotFound => {
+                return Err(Error::QueueError { msg: "Item not found".to_string() });
+            },
+            _ => self.ping(position_event(&queue)).await?,
+        }
+        self.discard_prefetch_if_changed(queue, next_before).await?;
+        Ok(())
+    }
+
+    // the prefetch only goes stale when the item after the current one changed,
+    // the broadcaster is told after the queue lock is released
+    async fn discard_prefetch_if_changed(&self, queue: MutexGuard<'_, PlayQueue>, next_before: String) -> Result<()> {
+        let next_changed = queue.peek_next() != next_before;
+        drop(queue);
+
+        if next_changed {
+            self.discard_prefetch().await?;
         }
         Ok(())
     }
@@ -253,10 +287,10 @@ impl Session {
         let mut queue = self.queue.lock().await;
         let key = queue.next();
         if key.is_empty() {
-            self.ping(queue.get_id()).await?;
+            self.ping(position_event(&queue)).await?;
             self.clean_active_file().await?;
         } else {
-            self.ping(queue.get_id()).await?;
+            self.ping(position_event(&queue)).await?;
             self.play(key).await?;
         }
         Ok(())
@@ -266,10 +300,10 @@ impl Session {
         let mut queue = self.queue.lock().await;
         let key = queue.prev();
         if key.is_empty() {
-            self.ping(queue.get_id()).await?;
+            self.ping(position_event(&queue)).await?;
             self.clean_active_file().await?;
         } else {
-            self.ping(queue.get_id()).await?;
+            self.ping(position_event(&queue)).await?;
             self.play(key).await?;
         }
         Ok(())
@@ -280,8 +314,11 @@ impl Session {
         let sender = self.update.clone();
         let queue = self.queue.clone();
 
+        // the broadcaster starts every new track unpaused
+        *self.paused.lock().await = false;
+
         tokio::spawn(async move {
-            sender.lock().await.send(queue.lock().await.get_id());
+            sender.lock().await.send(position_event(&*queue.lock().await));
         });
 
         self.broadcaster.cmd_tx.send(BroadcasterCommand::Stop).await
@@ -294,6 +331,67 @@ impl Session {
         Ok(())
     }
 
+    pub async fn pause(&self) -> Result<()> {
+
+        self.broadcaster.cmd_tx.send(BroadcasterCommand::Pause).await
+            .map_err(|e| { Error::BroadcasterError { msg: "Failed to pause broadcaster".to_string() }})?;
+
+        *self.paused.lock().await = true;
+        self.ping(json!({ "event": "pause" }).to_string()).await?;
+        Ok(())
+    }
+
+    pub async fn resume(&self) -> Result<()> {
+
+        self.broadcaster.cmd_tx.send(BroadcasterCommand::Resume).await
+            .map_err(|e| { Error::BroadcasterError { msg: "Failed to resume broadcaster".to_string() }})?;
+
+        *self.paused.lock().await = false;
+        self.ping(json!({ "event": "resume" }).to_string()).await?;
+        Ok(())
+    }
+
+    // the prefetched stream is only valid while the next queue item stays the same
+    pub async fn discard_prefetch(&self) -> Result<()> {
+
+        self.broadcaster.cmd_tx.send(BroadcasterCommand::DiscardPrefetch).await
+            .map_err(|e| { Error::BroadcasterError { msg: "Failed to discard prefetch".to_string() }})?;
+
+        Ok(())
+    }
+
+    pub async fn seek(&self, position_ms: u64) -> Result<()> {
+
+        self.broadcaster.cmd_tx.send(BroadcasterCommand::Seek { position_ms }).await
+            .map_err(|e| { Error::BroadcasterError { msg: "Failed to seek broadcaster".to_string() }})?;
+
+        Ok(())
+    }
+
+    pub async fn get_now_playing(&self) -> Result<NowPlaying> {
+        let now_playing = self.broadcaster.now_playing.lock().await;
+        Ok(now_playing.clone())
+    }
+
+    // 0 disables crossfading, longer durations are capped at MAX_CROSSFADE_MS
+    pub async fn set_crossfade(&self, duration_ms: u64) -> Result<()> {
+
+        let duration_ms = duration_ms.min(MAX_CROSSFADE_MS);
+        self.broadcaster.cmd_tx.send(BroadcasterCommand::SetCrossfade { duration_ms }).await
+            .map_err(|e| { Error::BroadcasterError { msg: "Failed to set crossfade".to_string() }})?;
+
+        *self.crossfade_ms.lock().await = duration_ms;
+        Ok(())
+    }
+
+    pub async fn get_crossfade(&self) -> Result<u64> {
+        Ok(*self.crossfade_ms.lock().await)
+    }
+
+    pub async fn is_paused(&self) -> Result<bool> {
+        Ok(*self.paused.lock().await)
+    }
+
     // spawn a running task to check for broadcaster end event
     pub async fn autoplay_loop(&self) -> Result<()> {
 
@@ -312,7 +410,7 @@ impl Session {
                     BroadcasterEvent::End => {
                         // handle the next item in the queue
                         let next_key = queue.lock().await.next();
-                        sender.lock().await.send(queue.lock().await.get_id());
+                        sender.lock().await.send(position_event(&*queue.lock().await));
                         if !next_key.is_empty() {
                             let _ = broadcaster
                                 .cmd_tx
@@ -322,6 +420,30 @@ impl Session {
                         }
 
                     },
+                    BroadcasterEvent::NearEnd => {
+                        let next_key = queue.lock().await.peek_next();
+                        let _ = broadcaster
+                            .cmd_tx
+                            .send(BroadcasterCommand::Prefetch { key: next_key })
+                        .await;
+                    },
+                    BroadcasterEvent::Seeked { position_ms } => {
+                        // let listeners jump their progress to the page the broadcaster landed on
+                        let msg = json!({
+                            "event": "seek",
+                            "position_ms": position_ms,
+                        });
+                        sender.lock().await.send(msg.to_string());
+                    },
+                    BroadcasterEvent::Progress { key, elapsed_ms, duration_ms } => {
+                        let msg = json!({
+                            "event": "progress",
+                            "key": key,
+                            "elapsed_ms": elapsed_ms,
+                            "duration_ms": duration_ms,
+                        });
+                        sender.lock().await.send(msg.to_string());
+                    },
                     _ => (),
                 }
             }
@@ -372,40 +494,7 @@ impl Session {
         ).await.map_err(|e| {
             Error::BroadcasterError { msg: "Failed to stop broadcaster".to_string() }
         })?;
-
-        let session_dir = format!("./sessions/{}", self.uuid.clone());
-
-        // check if the session directory exists
-        if !Path::new(&session_dir).exists() {
-            return Ok(());
-        }
-
-        let mut entries = match fs::read_dir(session_dir.clone()).await {
-            Ok(entries) => entries,
-            Err(e) => return Err(Error::ResetFileError { msg: e.to_string() }),
-        };
-
-        while let Some(entry) = entries.next_entry().await.transpose() {
-            let entry = entry?;
-            let path = entry.path();
-            if path.is_file() {
-                fs::remove_file(path).await?;
-            }
-        }
-        Ok(())
-    }
-
-    pub async fn clean_session_dir(&self) -> Result<()> {
-        let session_dir = format!("./sessions/{}", self.uuid.clone());
-
-        // check if the session directory exists
-        if !Path::new(&session_dir).exists() {
-            return Ok(());
-        }
-
-        // remove the session directory
-        fs::remove_dir_all(session_dir).await?;
-
+        *self.paused.lock().await = false;
         Ok(())
     }
 
@@ -530,16 +619,21 @@ pub struct SessionController{
     pub sessions: Arc<Mutex<HashMap<String, Option<Session>>>>,
     pub user_sessions: Arc<Mutex<HashMap<String, String>>>,
     pub file_manager: Arc<Mutex<FileManager>>,
+    pub storage: Arc<dyn StorageBackend>,
 }
 
 impl SessionController{
 
     pub async fn new() -> Result<Self> {
 
+        // one storage backend shared by the file manager and every broadcaster
+        let storage = storage::from_env().await?;
+
         let session_controller = Self {
             sessions: Arc::default(),
             user_sessions: Arc::default(),
-            file_manager: Arc::new(Mutex::new(FileManager::new().await?)),
+            file_manager: Arc::new(Mutex::new(FileManager::new(storage.clone()).await?)),
+            storage,
         };
 
         session_controller.session_collector_loop().await?;
@@ -567,22 +661,29 @@ impl SessionController{
 
         let peer_connections = Arc::new(Mutex::new(HashMap::new()));
 
+        let now_playing = Arc::new(Mutex::new(NowPlaying::default()));
+
+        // the broadcaster checks the queue before it crossfades into the next item
+        let queue = Arc::new(Mutex::new(PlayQueue::new()));
+
         // spin up the broadcaster
-        let broadcaster = Broadcaster::new(track, cmd_rx, event_tx, Arc::clone(&peer_connections), session_id.clone()).await?;
+        let broadcaster = Broadcaster::new(track, cmd_rx, event_tx, Arc::clone(&peer_connections), Arc::clone(&now_playing), self.storage.clone(), Arc::clone(&queue)).await?;
         tokio::spawn(async move {
             broadcaster.run().await;
         });
 
         let broadcaster_handle = BroadcasterHandle {
             cmd_tx,
-            event_rx: Arc::new(Mutex::new(event_rx))
+            event_rx: Arc::new(Mutex::new(event_rx)),
+            now_playing,
         };
         // create broadcaster and spin it on a task
         let mut session = Session::new(
             session_id.clone(), 
             user,
             broadcaster_handle, 
-            Arc::clone(&peer_connections)
+            Arc::clone(&peer_connections),
+            queue,
         ).await?;
 
         let mut sessions = self.sessions.lock().await;
@@ -603,8 +704,7 @@ impl SessionController{
                 match session {
                     Some(session) => {
                         session.clean_active_file().await?;
-                        session.clean_session_dir().await?;
-                        session.ping("end".to_string()).await?;
+                        session.ping(json!({ "event": "end" }).to_string()).await?;
                         sessions.remove(&session_id);
                         user_sessions.retain(|k, v| *v != session_id);
                     },
@@ -716,8 +816,7 @@ impl SessionController{
                                 Some(session) => {
                                     println!("->> Cleaning up session: {}", id);
                                     session.clean_active_file().await.unwrap();
-                                    session.clean_session_dir().await.unwrap();
-                                    // session.ping("end".to_string()).await.unwrap();
+                                    // session.ping(json!({ "event": "end" }).to_string()).await.unwrap();
                                     sessions.remove(&id);
                                     user_sessions.retain(|k, v| *v != id);
                                 },
@@ -732,4 +831,13 @@ impl SessionController{
 
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+// sse message sent whenever the current queue item changes
+fn position_event(queue: &PlayQueue) -> String {
+    json!({
+        "event": "position",
+        "index": queue.get_id(),
+        "item_id": queue.current_item_id(),
+    }).to_string()
+}
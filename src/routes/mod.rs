@@ -1,3 +1,5 @@
 pub mod routes_session;
 pub mod routes_control;
-pub mod routes_admin;
\ No newline at end of file
+pub mod routes_admin;
+pub mod routes_auth;
+pub mod routes_playlists;
\ No newline at end of file
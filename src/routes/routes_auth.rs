@@ -0,0 +1,233 @@
+use crate::middlewares::identity::{ IdentityProviders, LocalProvider };
+use crate::middlewares::mw::{ extract_credentials, resolve_ctx_cached };
+use crate::middlewares::session_token::SessionTokens;
+use crate::middlewares::token_cache::TokenCache;
+use crate::middlewares::{ REFRESH_TOKEN, SESSION_TOKEN };
+use crate::Ctx;
+
+use crate::Result;
+use serde::Deserialize;
+
+use crate::utils::error::Error;
+use axum::extract::Extension;
+use axum::http::HeaderMap;
+use axum::{Json, Router};
+use axum::routing::post;
+use serde_json::{json, Value};
+use sqlx::{ PgPool, Row };
+use tower_cookies::{ Cookie, Cookies };
+use tower_cookies::cookie::time::Duration;
+
+#[derive(Debug, Deserialize)]
+struct RegisterRequest {
+    username: String,
+    password: String,
+    name: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct LoginRequest {
+    provider: Option<String>,
+    token: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct RefreshRequest {
+    refresh_token: String,
+}
+
+pub fn routes() -> Router {
+    Router::new()
+        .route("/register", post(register))
+        .route("/login", post(login))
+        .route("/refresh", post(refresh))
+        .route("/logout", post(logout))
+}
+
+async fn register(
+    Extension(pool): Extension<PgPool>,
+    Extension(providers): Extension<IdentityProviders>,
+    Json(body): Json<RegisterRequest>,
+) -> Result<Json<Value>> {
+    println!("->> {:<12} - register", "Handler");
+
+    // local accounts only exist when the deployment enables the local provider
+    if !providers.has("local") {
+        return Err(Error::LoginFail);
+    }
+
+    if body.username.is_empty() || body.password.is_empty() || body.username.contains(':') {
+        return Err(Error::LoginFail);
+    }
+
+    let name = body.name.unwrap_or(body.username.clone());
+    LocalProvider::register(&pool, &body.username, &body.password, &name).await?;
+
+    Ok(Json(json!({
+        "status": "ok",
+        "message": "user registered",
+    })))
+}
+
+// sets both tokens as http only cookies and returns them for clients that do not use cookies
+fn token_response(cookies: &Cookies, tokens: &SessionTokens, access_token: String, refresh_token: String) -> Json<Value> {
+    cookies.add(
+        Cookie::build((SESSION_TOKEN, access_token.clone()))
+            .path("/")
+            .http_only(true)
+            .max_age(Duration::seconds(tokens.access_ttl as i64))
+            .build(),
+    );
+    cookies.add(
+        Cookie::build((REFRESH_TOKEN, refresh_token.clone()))
+            .path("/auth")
+            .http_only(true)
+            .max_age(Duration::seconds(tokens.refresh_ttl as i64))
+            .build(),
+    );
+
+    Json(json!({
+        "status": "ok",
+        "access_token": access_token,
+        "refresh_token": refresh_token,
+        "expires_in": tokens.access_ttl,
+    }))
+}
+
+/// Exchanges a provider token (body, provider cookie or Authorization header) for our own
+/// session token and a refresh token, the provider is not asked again until the next login.
+async fn login(
+    cookies: Cookies,
+    headers: HeaderMap,
+    Extension(pool): Extension<PgPool>,
+    Extension(providers): Extension<IdentityProviders>,
+    Extension(cache): Extension<TokenCache>,
+    Extension(tokens): Extension<SessionTokens>,
+    body: Option<Json<LoginRequest>>,
+) -> Result<Json<Value>> {
+    println!("->> {:<12} - login", "Handler");
+
+    let (provider, provider_token) = match body {
+        Some(Json(body)) => (providers.get(body.provider.as_deref())?, body.token),
+        None => extract_credentials(&cookies, &headers, &providers)?,
+    };
+
+    let ctx = resolve_ctx_cached(&pool, &cache, provider.as_ref(), &provider_token).await?;
+    let user_id: i32 = ctx.id().parse().map_err(|_| Error::AuthFailInvalidToken)?;
+
+    let sid = uuid::Uuid::new_v4().to_string();
+    let refresh_token = SessionTokens::new_refresh_token();
+
+    sqlx::query(
+        "INSERT INTO auth_sessions (session_id, user_id, refresh_hash, expires_at)
+         VALUES ($1, $2, $3, NOW() + make_interval(secs => $4))",
+    )
+    .bind(&sid)
+    .bind(user_id)
+    .bind(SessionTokens::hash_refresh_token(&refresh_token))
+    .bind(tokens.refresh_ttl as f64)
+    .execute(&pool)
+    .await
+    .map_err(|e| Error::DBError {
+        source: format!("{:?}", e),
+    })?;
+
+    let access_token = tokens.issue(&ctx, &sid)?;
+    Ok(token_response(&cookies, &tokens, access_token, refresh_token))
+}
+
+/// Rotates the refresh token and issues a new session token for the same auth session.
+async fn refresh(
+    cookies: Cookies,
+    Extension(pool): Extension<PgPool>,
+    Extension(tokens): Extension<SessionTokens>,
+    body: Option<Json<RefreshRequest>>,
+) -> Result<Json<Value>> {
+    println!("->> {:<12} - refresh", "Handler");
+
+    let refresh_token = match body {
+        Some(Json(body)) => body.refresh_token,
+        None => cookies
+            .get(REFRESH_TOKEN)
+            .map(|c| c.value().to_string())
+            .ok_or(Error::AuthFailNoToken)?,
+    };
+
+    let new_refresh_token = SessionTokens::new_refresh_token();
+
+    let row = sqlx::query(
+        "UPDATE auth_sessions s SET refresh_hash = $2
+         FROM users u
+         WHERE s.refresh_hash = $1 AND NOT s.revoked AND s.expires_at > NOW() AND u.user_id = s.user_id
+         RETURNING s.session_id, u.user_id, u.name, u.picture",
+    )
+    .bind(SessionTokens::hash_refresh_token(&refresh_token))
+    .bind(SessionTokens::hash_refresh_token(&new_refresh_token))
+    .fetch_optional(&pool)
+    .await
+    .map_err(|e| Error::DBError {
+        source: format!("{:?}", e),
+    })?
+    .ok_or(Error::AuthFailInvalidToken)?;
+
+    let sid: String = row.get("session_id");
+    let user_id: i32 = row.get("user_id");
+    let ctx = Ctx::new(user_id.to_string(), row.get("name"), row.get("picture"));
+
+    let access_token = tokens.issue(&ctx, &sid)?;
+    Ok(token_response(&cookies, &tokens, access_token, new_refresh_token))
+}
+
+/// Revokes the auth session behind the presented session or refresh token and clears the cookies.
+async fn logout(
+    cookies: Cookies,
+    headers: HeaderMap,
+    Extension(pool): Extension<PgPool>,
+    Extension(cache): Extension<TokenCache>,
+    Extension(tokens): Extension<SessionTokens>,
+) -> Result<Json<Value>> {
+    println!("->> {:<12} - logout", "Handler");
+
+    let access_token = cookies
+        .get(SESSION_TOKEN)
+        .map(|c| c.value().to_string())
+        .or_else(|| {
+            headers
+                .get(axum::http::header::AUTHORIZATION)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.strip_prefix("Bearer "))
+                .map(str::to_string)
+        });
+    let refresh_token = cookies.get(REFRESH_TOKEN).map(|c| c.value().to_string());
+
+    let row = if let Some(claims) = access_token.and_then(|t| tokens.verify(&t).ok()) {
+        sqlx::query("UPDATE auth_sessions SET revoked = TRUE WHERE session_id = $1 RETURNING session_id, user_id")
+            .bind(claims.sid)
+            .fetch_optional(&pool)
+            .await
+    } else if let Some(refresh_token) = refresh_token {
+        sqlx::query("UPDATE auth_sessions SET revoked = TRUE WHERE refresh_hash = $1 RETURNING session_id, user_id")
+            .bind(SessionTokens::hash_refresh_token(&refresh_token))
+            .fetch_optional(&pool)
+            .await
+    } else {
+        return Err(Error::AuthFailNoToken);
+    }
+    .map_err(|e| Error::DBError {
+        source: format!("{:?}", e),
+    })?;
+
+    if let Some(row) = row {
+        let user_id: i32 = row.get("user_id");
+        tokens.revoke(&row.get::<String, _>("session_id"));
+        cache.invalidate_user(&user_id.to_string());
+    }
+
+    cookies.remove(Cookie::build(SESSION_TOKEN).path("/").build());
+    cookies.remove(Cookie::build(REFRESH_TOKEN).path("/auth").build());
+
+    Ok(Json(json!({
+        "status": "ok",
+        "message": "logged out",
+    })))
+}
@@ -1,12 +1,16 @@
 use std::sync::Arc;
 use axum::Router;
 use axum::routing::{ get, post };
-use axum::extract::State;
+use axum::extract::{ DefaultBodyLimit, Multipart, State };
 use axum::Extension;
 use sqlx::PgPool;
 use serde_json::{json, Value};
 use axum::{Json};
-use serde::Deserialize;
+use serde::{ Deserialize, Serialize };
+use sqlx::{ Postgres, QueryBuilder };
+use sqlx::postgres::PgRow;
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
 use tokio::sync::broadcast;
 use axum::response::{
     Sse,
@@ -20,13 +24,80 @@ use tokio_stream::{StreamExt};
 // use the Result enum
 use core::result::Result as CoreResult;
 use sqlx::Row;
+use std::path::Path;
+use tokio::io::AsyncWriteExt;
 
 use crate::utils::error::{ Error, Result, ClientError };
 use crate::models::SessionController;
 use crate::ctx::Ctx;
-use crate::media::file_manager::{ FileManager, FMDownloadParams};
+use crate::media::file_manager::{ FileManager, FMDownloadParams, TempFile, TrackMetadata, UPLOAD_FORMATS };
+use crate::media::jobs::JobRunner;
 use crate::models::session::User;
 
+// uploads stream with the body limit disabled, so every other field needs its own cap
+const MAX_TITLE_BYTES: usize = 1024;
+
+#[derive(Debug, Deserialize)]
+struct FileListQuery {
+    cursor: Option<String>,
+    limit: Option<i64>,
+    // "date" (default), "name" or "duration"
+    sort: Option<String>,
+    order: Option<String>,
+    // unix seconds, from is inclusive and to exclusive
+    from: Option<i64>,
+    to: Option<i64>,
+    domain: Option<String>,
+}
+
+// position in a sorted file listing, opaque to clients
+#[derive(Debug, Serialize, Deserialize)]
+struct FileCursor {
+    // the listing the cursor came from, a cursor only continues the same sort and order
+    sort: String,
+    descending: bool,
+    value: Value,
+    id: i32,
+}
+
+impl FileCursor {
+    fn from_row(row: &PgRow, sort: &str, descending: bool) -> Self {
+        let value = match sort {
+            "name" => json!(row.get::<String, &str>("sort_value")),
+            "duration" => json!(row.get::<i64, &str>("sort_value")),
+            _ => json!(row.get::<f64, &str>("sort_value")),
+        };
+        Self { sort: sort.to_string(), descending, value, id: row.get("file_id") }
+    }
+
+    fn encode(&self) -> String {
+        URL_SAFE_NO_PAD.encode(serde_json::to_vec(self).unwrap_or_default())
+    }
+
+    fn decode(cursor: &str) -> Result<Self> {
+        URL_SAFE_NO_PAD
+            .decode(cursor)
+            .ok()
+            .and_then(|bytes| serde_json::from_slice(&bytes).ok())
+            .ok_or(Error::InvalidRequest { msg: "invalid cursor".to_string() })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct SearchQuery {
+    q: String,
+    // "mine" (default) or "public"
+    scope: Option<String>,
+    page: Option<i64>,
+    page_size: Option<i64>,
+}
+
+#[derive(Debug, Deserialize)]
+struct FileVisibility {
+    key: String,
+    public: bool,
+}
+
 #[derive(Debug, Deserialize)]
 struct PlayTestRequest {
     url: String,
@@ -43,6 +114,11 @@ struct DeleteFile {
     key: String,
 }
 
+#[derive(Debug, Deserialize)]
+struct FileKeyQuery {
+    key: String,
+}
+
 #[derive(Debug, Deserialize)]
 struct AddQueue {
     session_id: String,
@@ -53,14 +129,15 @@ struct AddQueue {
 #[derive(Debug, Deserialize)]
 struct RemoveQueue {
     session_id: String,
-    index: usize,
+    item_id: u64,
 }
 
 #[derive(Debug, Deserialize)]
 struct ReorderQueue {
     session_id: String,
-    old_index: usize,
-    new_index: usize,
+    item_id: u64,
+    // the item is moved in front of this one, or to the end when left out
+    before_item_id: Option<u64>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -68,19 +145,48 @@ struct NextQueue {
     session_id: String,
 }
 
+#[derive(Debug, Deserialize)]
+struct PlaybackControl {
+    session_id: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct CrossfadeRequest {
+    session_id: String,
+    duration_ms: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct SeekRequest {
+    session_id: String,
+    position_ms: u64,
+}
+
 pub fn routes(mc: Arc<SessionController>) -> Router {
     Router::new()
         .route("/me", get(me))
         .route("/get_metadata", post(get_metadata))
         .route("/download", post(download))
+        // the size limit is enforced against max_file_size while the file streams in
+        .route("/upload", post(upload).layer(DefaultBodyLimit::disable()))
         .route("/create_session", get(create_session))
         .route("/get_files", get(get_files))
+        .route("/search", get(search))
+        .route("/set_file_visibility", post(set_file_visibility))
+        .route("/file_metadata", get(file_metadata))
         .route("/add_to_queue", post(add_to_queue))
         .route("/remove_from_queue", post(remove_from_queue))
         .route("/reorder_queue", post(reorder_queue))
         .route("/next_in_queue", post(next_in_queue))
         .route("/prev_in_queue", post(prev_in_queue))
+        .route("/pause", post(pause))
+        .route("/resume", post(resume))
+        .route("/seek", post(seek))
+        .route("/set_crossfade", post(set_crossfade))
         .route("/download_notify", get(download_notify))
+        .route("/jobs", get(list_jobs))
+        .route("/jobs/:id", get(get_job))
+        .route("/jobs/:id/cancel", post(cancel_job))
         .route("/delete_session", get(delete_session))
         .route("/delete_file", post(delete_file))
         .route("/delete_all_files", get(delete_all_files))
@@ -99,11 +205,15 @@ async fn me(
     let session = mc.get_user_session(id.clone()).await?;
     println!("->> user: {}, name: {}", id, name);
 
+    let fm = mc.get_file_manager().await?;
+    let quota = fm.quota.usage(&pool, id.parse::<i32>().unwrap()).await?;
+
     Ok(Json(json!({
         "id": id,
         "name": name,
         "picture": picture,
         "session": session,
+        "quota": quota,
     })))
 }
 
@@ -138,23 +248,104 @@ async fn get_files(
     State(mc): State<Arc<SessionController>>,
     Extension(pool): Extension<PgPool>,
     ctx: Ctx,
+    axum::extract::Query(params): axum::extract::Query<FileListQuery>,
 ) -> Result<Json<Value>> {
     println!("->> {:<12} - get_files", "Handler");
 
     let id = ctx.id();
-    let name = ctx.name();
-    
-    match sqlx::query("SELECT uuid, name FROM files WHERE user_id = $1 ORDER BY created_at DESC")
-        .bind(&id.parse::<i32>().unwrap())
-        .fetch_all(&pool)
-        .await {
-            Ok(files) => {
+    let limit = params.limit.unwrap_or(50).clamp(1, 200);
+
+    // the sort column is picked from a fixed list, never taken from the request
+    let sort = params.sort.as_deref().unwrap_or("date");
+    let sort_expr = match sort {
+        "date" => "EXTRACT(EPOCH FROM created_at)::DOUBLE PRECISION",
+        "name" => "LOWER(COALESCE(name, ''))",
+        "duration" => "COALESCE(duration_ms, 0)::BIGINT",
+        _ => return Err(Error::InvalidRequest { msg: "sort must be date, name or duration".to_string() }),
+    };
+    let descending = match params.order.as_deref() {
+        None => sort != "name",
+        Some("desc") => true,
+        Some("asc") => false,
+        Some(_) => return Err(Error::InvalidRequest { msg: "order must be asc or desc".to_string() }),
+    };
+
+    let mut query = QueryBuilder::<Postgres>::new(format!(
+        "SELECT file_id, uuid, name, url, duration_ms, artist, thumbnail, original_format,
+                EXTRACT(EPOCH FROM created_at)::BIGINT AS created_at, {} AS sort_value
+         FROM files WHERE user_id = ",
+        sort_expr
+    ));
+    query.push_bind(id.parse::<i32>().unwrap());
+
+    // created_at has no time zone, the epoch is taken the same way as in the output and the
+    // date cursor so the range does not shift with the session TimeZone
+    if let Some(from) = params.from {
+        query.push(" AND EXTRACT(EPOCH FROM created_at) >= ").push_bind(from);
+    }
+    if let Some(to) = params.to {
+        query.push(" AND EXTRACT(EPOCH FROM created_at) < ").push_bind(to);
+    }
+    if let Some(domain) = params.domain.as_deref() {
+        // matches the domain itself and its subdomains, www. is ignored on both sides
+        let domain = domain.trim().trim_start_matches("www.").to_lowercase();
+        // compared as plain strings, a LIKE pattern would treat % and _ in the input as wildcards
+        let host = "LOWER(SUBSTRING(url FROM '://(?:www\\.)?([^/:?#]+)'))";
+        let suffix = format!(".{}", domain);
+        query.push(format!(" AND ({} = ", host))
+            .push_bind(domain.clone())
+            .push(format!(" OR RIGHT({}, ", host))
+            .push_bind(suffix.chars().count() as i32)
+            .push(") = ")
+            .push_bind(suffix)
+            .push(")");
+    }
+
+    // keyset pagination, the cursor is the sort value and id of the last row returned
+    if let Some(cursor) = params.cursor.as_deref() {
+        let cursor = FileCursor::decode(cursor)?;
+        if cursor.sort != sort || cursor.descending != descending {
+            return Err(Error::InvalidRequest { msg: "cursor belongs to a different sort or order".to_string() });
+        }
+        let invalid = || Error::InvalidRequest { msg: "invalid cursor".to_string() };
+
+        query.push(format!(" AND ({}, file_id) {} (", sort_expr, if descending { "<" } else { ">" }));
+        match sort {
+            "name" => query.push_bind(cursor.value.as_str().ok_or_else(invalid)?.to_string()),
+            "duration" => query.push_bind(cursor.value.as_i64().ok_or_else(invalid)?),
+            _ => query.push_bind(cursor.value.as_f64().ok_or_else(invalid)?),
+        };
+        query.push(", ").push_bind(cursor.id).push(")");
+    }
+
+    let direction = if descending { "DESC" } else { "ASC" };
+    query.push(format!(" ORDER BY sort_value {}, file_id {} LIMIT ", direction, direction));
+    query.push_bind(limit + 1);
+
+    match query.build().fetch_all(&pool).await {
+            Ok(mut files) => {
+                // one extra row tells whether there is another page
+                let next_cursor = if files.len() as i64 > limit {
+                    files.truncate(limit as usize);
+                    files.last().map(|f| FileCursor::from_row(f, sort, descending).encode())
+                } else {
+                    None
+                };
+
                 Ok(Json(json!({
                     "status": "ok",
+                    "next_cursor": next_cursor,
                     "files": files.iter().map(|f| {
+                        let metadata = TrackMetadata::from_row(f);
                         json!({
                             "uuid": f.get::<String, &str>("uuid"),
                             "name": f.get::<String, &str>("name"),
+                            "url": f.get::<String, &str>("url"),
+                            "created_at": f.get::<i64, &str>("created_at"),
+                            "duration_ms": metadata.duration_ms,
+                            "artist": metadata.artist,
+                            "thumbnail": metadata.thumbnail,
+                            "original_format": metadata.original_format,
                         })
                     }).collect::<Vec<Value>>()
                 })))
@@ -167,6 +358,133 @@ async fn get_files(
         }
 }
 
+// turns free text into a prefix tsquery, "daft-pun" -> "daft:* & pun:*",
+// split like the english parser splits names so hyphenated words still match
+fn prefix_tsquery(q: &str) -> String {
+    q.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(|word| format!("{}:*", word))
+        .collect::<Vec<String>>()
+        .join(" & ")
+}
+
+async fn search(
+    ctx: Ctx,
+    Extension(pool): Extension<PgPool>,
+    axum::extract::Query(params): axum::extract::Query<SearchQuery>,
+) -> Result<Json<Value>> {
+    println!("->> {:<12} - search", "Handler");
+
+    let user_id = ctx.id().parse::<i32>().unwrap();
+    let page = params.page.unwrap_or(1).max(1);
+    let page_size = params.page_size.unwrap_or(20).clamp(1, 100);
+    let public = match params.scope.as_deref() {
+        None | Some("mine") => false,
+        Some("public") => true,
+        Some(_) => return Err(Error::InvalidRequest { msg: "scope must be mine or public".to_string() }),
+    };
+
+    let tsquery = prefix_tsquery(&params.q);
+    if tsquery.is_empty() {
+        return Ok(Json(json!({
+            "status": "ok",
+            "total": 0,
+            "page": page,
+            "page_size": page_size,
+            "files": [],
+        })));
+    }
+
+    let rows = sqlx::query(
+        "SELECT uuid, name, user_id, duration_ms, artist, thumbnail, original_format,
+                ts_rank(name_tsv, query) AS rank, COUNT(*) OVER() AS total
+         FROM files, to_tsquery('english', $1) AS query
+         WHERE name_tsv @@ query
+           AND (CASE WHEN $2 THEN is_public ELSE user_id = $3 END)
+         ORDER BY rank DESC, created_at DESC
+         LIMIT $4 OFFSET $5"
+    )
+        .bind(&tsquery)
+        .bind(public)
+        .bind(user_id)
+        .bind(page_size)
+        .bind((page - 1) * page_size)
+        .fetch_all(&pool)
+        .await
+        .map_err(|e| Error::DBError { source: e.to_string() })?;
+
+    let total: i64 = rows.first().map(|r| r.get("total")).unwrap_or(0);
+
+    Ok(Json(json!({
+        "status": "ok",
+        "total": total,
+        "page": page,
+        "page_size": page_size,
+        "files": rows.iter().map(|f| {
+            let metadata = TrackMetadata::from_row(f);
+            json!({
+                "uuid": f.get::<String, &str>("uuid"),
+                "name": f.get::<String, &str>("name"),
+                "owned": f.get::<i32, &str>("user_id") == user_id,
+                "rank": f.get::<f32, &str>("rank"),
+                "duration_ms": metadata.duration_ms,
+                "artist": metadata.artist,
+                "thumbnail": metadata.thumbnail,
+                "original_format": metadata.original_format,
+            })
+        }).collect::<Vec<Value>>()
+    })))
+}
+
+// a public file shows up in everyone's search with scope=public, and any signed-in user
+// can read its metadata at /file_metadata and queue it in their own sessions.
+// it never joins their library, playlists or quota, and only the owner can change it
+async fn file_metadata(
+    ctx: Ctx,
+    Extension(pool): Extension<PgPool>,
+    axum::extract::Query(params): axum::extract::Query<FileKeyQuery>,
+) -> Result<Json<Value>> {
+    println!("->> {:<12} - file_metadata", "Handler");
+
+    let user_id = ctx.id().parse::<i32>().map_err(|_| Error::AuthFailInvalidToken)?;
+    let (name, metadata) = FileManager::get_playable_file(&pool, user_id, &params.key).await?;
+
+    Ok(Json(json!({
+        "status": "ok",
+        "uuid": params.key,
+        "name": name,
+        "duration_ms": metadata.duration_ms,
+        "artist": metadata.artist,
+        "thumbnail": metadata.thumbnail,
+        "original_format": metadata.original_format,
+    })))
+}
+
+async fn set_file_visibility(
+    ctx: Ctx,
+    Extension(pool): Extension<PgPool>,
+    Json(body): Json<FileVisibility>,
+) -> Result<Json<Value>> {
+    println!("->> {:<12} - set_file_visibility", "Handler");
+
+    let result = sqlx::query("UPDATE files SET is_public = $1 WHERE uuid = $2 AND user_id = $3")
+        .bind(body.public)
+        .bind(&body.key)
+        .bind(ctx.id().parse::<i32>().unwrap())
+        .execute(&pool)
+        .await
+        .map_err(|e| Error::DBError { source: e.to_string() })?;
+
+    if result.rows_affected() == 0 {
+        return Err(Error::ContentNotFound { msg: body.key });
+    }
+
+    Ok(Json(json!({
+        "status": "ok",
+        "message": "visibility updated",
+    })))
+}
+
 async fn download_notify(
     ctx: Ctx,
     State(mc): State<Arc<SessionController>>,
@@ -197,7 +515,7 @@ async fn download_notify(
 async fn download(
     ctx: Ctx,
     State(mc): State<Arc<SessionController>>,
-    Extension(pool): Extension<PgPool>,
+    Extension(jobs): Extension<JobRunner>,
     Json(body): Json<DownloadRequest>,
 ) -> Result<Json<Value>> {
     println!("->> {:<12} - download", "Handler");
@@ -209,32 +527,159 @@ async fn download(
     // has to call fm.process_audio directly instead of wrapping
     // the function with session controller to ensure concurrent downloads
 
+    // every url becomes a download job, the runner keeps downloads concurrent
+    let mut job_ids = Vec::new();
+    for (url, title) in body.urls.iter().zip(body.titles.iter()) {
+        job_ids.push(jobs.submit(&user_id, url.clone(), title.clone()).await?);
+    }
+    
+    Ok(Json(json!({
+        "status": "ok",
+        "message": "Download initiated",
+        "job_ids": job_ids,
+    })))
+}
+
+async fn list_jobs(
+    ctx: Ctx,
+    Extension(jobs): Extension<JobRunner>,
+) -> Result<Json<Value>> {
+    println!("->> {:<12} - list_jobs", "Handler");
+
+    Ok(Json(json!({
+        "status": "ok",
+        "jobs": jobs.list(&ctx.id()).await?,
+    })))
+}
+
+async fn get_job(
+    ctx: Ctx,
+    Extension(jobs): Extension<JobRunner>,
+    axum::extract::Path(job_id): axum::extract::Path<i32>,
+) -> Result<Json<Value>> {
+    println!("->> {:<12} - get_job", "Handler");
+
+    Ok(Json(json!({
+        "status": "ok",
+        "job": jobs.get(&ctx.id(), job_id).await?,
+    })))
+}
+
+async fn cancel_job(
+    ctx: Ctx,
+    Extension(jobs): Extension<JobRunner>,
+    axum::extract::Path(job_id): axum::extract::Path<i32>,
+) -> Result<Json<Value>> {
+    println!("->> {:<12} - cancel_job", "Handler");
+
+    jobs.cancel(&ctx.id(), job_id).await?;
+
+    Ok(Json(json!({
+        "status": "ok",
+        "message": "job cancelled",
+    })))
+}
+
+async fn upload(
+    ctx: Ctx,
+    State(mc): State<Arc<SessionController>>,
+    Extension(pool): Extension<PgPool>,
+    mut multipart: Multipart,
+) -> Result<Json<Value>> {
+    println!("->> {:<12} - upload", "Handler");
+
+    let user_id = ctx.id();
     let fm = mc.get_file_manager().await?;
 
-    for i in 0..body.urls.len() {
-        let url = body.urls[i].clone();
-        let title = body.titles[i].clone();
-
-        let pool = pool.clone();
-        let user_id = user_id.clone();
-        let fm = fm.clone();
-
-        tokio::spawn(async move {
-            fm.process_audio(
-                FMDownloadParams {
-                    url: url.clone(),
-                    title: title.clone(),
-                    userid: user_id.clone(),
-                    pool: pool.clone(),
+    let mut title = String::new();
+    // the temp file is removed on every early return, process_upload takes the guard over
+    let mut upload: Option<(TempFile, String, String)> = None;
+
+    while let Some(mut field) = multipart.next_field().await
+        .map_err(|e| Error::UploadFailed { msg: e.to_string() })?
+    {
+        match field.name() {
+            Some("title") => {
+                // the body limit is off for this route, so the title is capped while it streams in
+                let mut bytes = Vec::new();
+                while let Some(chunk) = field.chunk().await
+                    .map_err(|e| Error::UploadFailed { msg: e.to_string() })?
+                {
+                    if bytes.len() + chunk.len() > MAX_TITLE_BYTES {
+                        return Err(Error::InvalidRequest {
+                            msg: format!("title can be at most {} bytes", MAX_TITLE_BYTES),
+                        });
+                    }
+                    bytes.extend_from_slice(&chunk);
+                }
+                title = String::from_utf8(bytes)
+                    .map_err(|_| Error::InvalidRequest { msg: "title must be valid utf-8".to_string() })?;
+            },
+            Some("file") => {
+                if upload.is_some() {
+                    return Err(Error::InvalidRequest { msg: "only one file per upload".to_string() });
+                }
+
+                let file_name = field.file_name().unwrap_or("").to_string();
+                let format = Path::new(&file_name)
+                    .extension()
+                    .and_then(|ext| ext.to_str())
+                    .unwrap_or("")
+                    .to_lowercase();
+
+                if !UPLOAD_FORMATS.contains(&format.as_str()) {
+                    return Err(Error::UnsupportedFormat { format });
+                }
+
+                let temp = TempFile::new(FileManager::upload_path(&format).await?);
+                let mut file = tokio::fs::File::create(temp.path()).await?;
+                let mut size: u64 = 0;
+
+                while let Some(chunk) = field.chunk().await
+                    .map_err(|e| Error::UploadFailed { msg: e.to_string() })?
+                {
+                    size += chunk.len() as u64;
+                    if size > fm.max_file_size {
+                        return Err(Error::FileTooLarge { size, limit: fm.max_file_size });
+                    }
+                    file.write_all(&chunk).await?;
                 }
-            ).await?;
-            Ok::<(), Error>(())
-        });
+                file.flush().await?;
+
+                upload = Some((temp, format, file_name));
+            },
+            _ => (),
+        }
     }
-    
+
+    let (temp, format, file_name) = upload
+        .ok_or(Error::UploadFailed { msg: "No file in upload".to_string() })?;
+
+    // fall back to the file name without its extension
+    if title.trim().is_empty() {
+        title = Path::new(&file_name)
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .unwrap_or("untitled")
+            .to_string();
+    }
+
+    let uuid = fm.process_upload(
+        FMDownloadParams {
+            url: format!("upload://{}", file_name),
+            title: title.trim().to_string(),
+            userid: user_id.clone(),
+            pool: pool.clone(),
+            job_id: None,
+        },
+        temp,
+        format,
+    ).await?;
+
     Ok(Json(json!({
         "status": "ok",
-        "message": "Download initiated",
+        "message": "Upload complete",
+        "uuid": uuid,
     })))
 }
 
@@ -274,6 +719,7 @@ async fn get_metadata(
 async fn add_to_queue(
     ctx: Ctx,
     State(mc): State<Arc<SessionController>>,
+    Extension(pool): Extension<PgPool>,
     Json(body): Json<AddQueue>,
 ) -> Result<Json<Value>> {
     println!("->> {:<12} - add_to_queue", "Handler");
@@ -290,11 +736,16 @@ async fn add_to_queue(
     let title = body.title.clone();
     let session = mc.get_session(session_id).await?;
 
-    session.add_to_queue(key, title).await?;
+    // only the user's own files and public files can be queued
+
+    let uid = user_id.parse::<i32>().map_err(|_| Error::AuthFailInvalidToken)?;
+    let (_, metadata) = FileManager::get_playable_file(&pool, uid, &key).await?;
+    let item_id = session.add_to_queue(key, title, user_id, metadata.duration_ms).await?;
     
     Ok(Json(json!({
         "status": "ok",
         "message": "Download initiated",
+        "item_id": item_id,
     })))
 }
 
@@ -312,10 +763,9 @@ async fn remove_from_queue(
         return Err(Error::SessionNotOwned);
     }
 
-    let index = body.index.clone();
     let session = mc.get_session(session_id).await?;
 
-    session.remove_from_queue(index).await?;
+    session.remove_from_queue(body.item_id).await?;
     
     Ok(Json(json!({
         "status": "ok",
@@ -337,11 +787,9 @@ async fn reorder_queue(
         return Err(Error::SessionNotOwned);
     }
 
-    let old_index = body.old_index;
-    let new_index = body.new_index;
     let session = mc.get_session(session_id).await?;
 
-    session.reorder_queue(old_index, new_index).await?;
+    session.reorder_queue(body.item_id, body.before_item_id).await?;
     
     Ok(Json(json!({
         "status": "ok",
@@ -395,6 +843,99 @@ async fn prev_in_queue(
     }))
 )}
 
+async fn pause(
+    ctx: Ctx,
+    State(mc): State<Arc<SessionController>>,
+    Json(body): Json<PlaybackControl>,
+) -> Result<Json<Value>> {
+    println!("->> {:<12} - pause", "Handler");
+
+    let user_id = ctx.id();
+    let session_id = body.session_id.clone();
+
+    if (!mc.check_user_own_session(user_id.clone(), session_id.clone()).await?) {
+        return Err(Error::SessionNotOwned);
+    }
+
+    let session = mc.get_session(session_id).await?;
+    session.pause().await?;
+    
+    Ok(Json(json!({
+        "status": "ok",
+        "message": "paused",
+    }))
+)}
+
+async fn resume(
+    ctx: Ctx,
+    State(mc): State<Arc<SessionController>>,
+    Json(body): Json<PlaybackControl>,
+) -> Result<Json<Value>> {
+    println!("->> {:<12} - resume", "Handler");
+
+    let user_id = ctx.id();
+    let session_id = body.session_id.clone();
+
+    if (!mc.check_user_own_session(user_id.clone(), session_id.clone()).await?) {
+        return Err(Error::SessionNotOwned);
+    }
+
+    let session = mc.get_session(session_id).await?;
+    session.resume().await?;
+    
+    Ok(Json(json!({
+        "status": "ok",
+        "message": "resumed",
+    }))
+)}
+
+async fn seek(
+    ctx: Ctx,
+    State(mc): State<Arc<SessionController>>,
+    Json(body): Json<SeekRequest>,
+) -> Result<Json<Value>> {
+    println!("->> {:<12} - seek", "Handler");
+
+    let user_id = ctx.id();
+    let session_id = body.session_id.clone();
+
+    if (!mc.check_user_own_session(user_id.clone(), session_id.clone()).await?) {
+        return Err(Error::SessionNotOwned);
+    }
+
+    let session = mc.get_session(session_id).await?;
+    session.seek(body.position_ms).await?;
+    
+    Ok(Json(json!({
+        "status": "ok",
+        "message": "seek",
+    }))
+)}
+
+async fn set_crossfade(
+    ctx: Ctx,
+    State(mc): State<Arc<SessionController>>,
+    Json(body): Json<CrossfadeRequest>,
+) -> Result<Json<Value>> {
+    println!("->> {:<12} - set_crossfade", "Handler");
+
+    let user_id = ctx.id();
+    let session_id = body.session_id.clone();
+
+    if (!mc.check_user_own_session(user_id.clone(), session_id.clone()).await?) {
+        return Err(Error::SessionNotOwned);
+    }
+
+    let session = mc.get_session(session_id).await?;
+    session.set_crossfade(body.duration_ms).await?;
+    
+    Ok(Json(json!({
+        "status": "ok",
+        "message": "crossfade set",
+        "duration_ms": session.get_crossfade().await?,
+    }))
+)}
+
 async fn delete_session(
     ctx: Ctx,
     State(mc): State<Arc<SessionController>>,
@@ -432,32 +973,27 @@ async fn delete_file(
     let key = body.key.clone();
 
     // check if user has file
-    let file = sqlx::query("SELECT * FROM files WHERE uuid = $1")
+    // files are shared by key, only the user's own reference is deleted
+    let file = sqlx::query("SELECT * FROM files WHERE uuid = $1 AND user_id = $2")
         .bind(&key)
-        .fetch_one(&pool)
-        .await?;
+        .bind(user_id.parse::<i32>().unwrap())
+        .fetch_optional(&pool)
+        .await?
+        .ok_or(Error::ContentNotFound { msg: "User does not own file".to_string() })?;
 
-    if file.get::<i32, &str>("user_id") != user_id.parse::<i32>().unwrap() {
-        return Err(Error::S3Error { msg: "User does not own file".to_string() });
-    }
-
-    // has to remove file from session queue
+    // has to remove the items the user queued, others may have queued the same public file
     let session = mc.get_session(mc.get_user_session(user_id.clone()).await?).await?;
     if session.has_file_in_queue(key.clone()).await? {
-        session.remove_key_from_queue(key.clone()).await?;
+        session.remove_key_from_queue(key.clone(), &user_id).await?;
     }
 
     let uuid = file.get::<String, &str>("uuid");
+    let fm = mc.get_file_manager().await?;
 
-    // delete file from disk
-    mc.file_manager.lock().await.delete_file(uuid.clone()).await?;
-
-    sqlx::query(
-        "DELETE FROM files WHERE uuid = $1"
-    )
-        .bind(uuid.clone())
-        .execute(&pool)
-        .await?;
+    // the row goes first so the quota counters never miss a deleted file
+    if let Some(uuid) = fm.delete_file_record(&pool, user_id.parse::<i32>().unwrap(), &uuid).await? {
+        fm.delete_file(uuid).await?;
+    }
 
     Ok(Json(json!({
         "status": "ok",
@@ -484,20 +1020,15 @@ async fn delete_all_files(
         // has to remove file from session queue
         let session = mc.get_session(mc.get_user_session(user_id.clone()).await?).await?;
         if session.has_file_in_queue(key.clone()).await? {
-            session.remove_key_from_queue(key.clone()).await?;
+            session.remove_key_from_queue(key.clone(), &user_id).await?;
         }
 
         let uuid = file.get::<String, &str>("uuid");
+        let fm = mc.get_file_manager().await?;
 
-        // delete file from disk
-        mc.file_manager.lock().await.delete_file(uuid.clone()).await?;
-
-        sqlx::query(
-            "DELETE FROM files WHERE uuid = $1"
-        )
-            .bind(uuid.clone())
-            .execute(&pool)
-            .await?;
+        if let Some(uuid) = fm.delete_file_record(&pool, user_id.parse::<i32>().unwrap(), &uuid).await? {
+            fm.delete_file(uuid).await?;
+        }
     }
 
     Ok(Json(json!({
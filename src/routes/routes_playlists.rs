@@ -0,0 +1,368 @@
+use crate::models::SessionController;
+use crate::models::playlist::Playlist;
+use crate::models::playlist_format::{ ImportEntry, PlaylistFormat };
+use crate::media::blobs::normalize_source_url;
+use crate::media::jobs::JobRunner;
+use crate::ctx::Ctx;
+
+use crate::utils::error::{ Error, Result };
+use serde::Deserialize;
+use axum::extract::{ Path, Query, State };
+use axum::http::header;
+use axum::response::{ IntoResponse, Response };
+use axum::{ Extension, Json, Router };
+use axum::routing::{ get, post };
+use serde_json::{ json, Value };
+use sqlx::{ PgPool, Row };
+use std::collections::HashMap;
+use std::sync::Arc;
+
+// every entry may become a download job, so imports are kept to a sane size
+const MAX_IMPORT_ENTRIES: usize = 500;
+
+#[derive(Debug, Deserialize)]
+struct CreatePlaylist {
+    name: String,
+    description: Option<String>,
+    // file keys in play order
+    #[serde(default)]
+    keys: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct UpdatePlaylist {
+    name: Option<String>,
+    // left out keeps the description, null clears it
+    #[serde(default, with = "::serde_with::rust::double_option")]
+    description: Option<Option<String>>,
+    keys: Option<Vec<String>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct LoadPlaylist {
+    session_id: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct SaveQueue {
+    session_id: String,
+    name: String,
+    description: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ExportQuery {
+    #[serde(default = "default_format")]
+    format: String,
+}
+
+fn default_format() -> String {
+    "json".to_string()
+}
+
+#[derive(Debug, Deserialize)]
+struct ImportPlaylist {
+    // m3u, xspf or json
+    format: String,
+    content: String,
+    // falls back to the name inside the file
+    name: Option<String>,
+    description: Option<String>,
+}
+
+pub fn routes(mc: Arc<SessionController>) -> Router {
+    Router::new()
+        .route("/playlists", get(list_playlists).post(create_playlist))
+        .route("/playlists/from_queue", post(save_queue))
+        .route("/playlists/:id", get(get_playlist).put(update_playlist).delete(delete_playlist))
+        .route("/playlists/import", post(import_playlist))
+        .route("/playlists/:id/load", post(load_playlist))
+        .route("/playlists/:id/export", get(export_playlist))
+        .with_state(mc)
+}
+
+fn user_id(ctx: &Ctx) -> Result<i32> {
+    ctx.id().parse::<i32>().map_err(|_| Error::AuthFailInvalidToken)
+}
+
+async fn list_playlists(
+    ctx: Ctx,
+    Extension(pool): Extension<PgPool>,
+) -> Result<Json<Value>> {
+    println!("->> {:<12} - list_playlists", "Handler");
+
+    Ok(Json(json!({
+        "status": "ok",
+        "playlists": Playlist::list(&pool, user_id(&ctx)?).await?,
+    })))
+}
+
+async fn create_playlist(
+    ctx: Ctx,
+    Extension(pool): Extension<PgPool>,
+    Json(body): Json<CreatePlaylist>,
+) -> Result<Json<Value>> {
+    println!("->> {:<12} - create_playlist", "Handler");
+
+    if body.name.trim().is_empty() {
+        return Err(Error::InvalidRequest { msg: "playlist name is empty".to_string() });
+    }
+
+    let (playlist_id, skipped) = Playlist::create(
+        &pool,
+        user_id(&ctx)?,
+        body.name.trim(),
+        body.description.as_deref(),
+        &body.keys,
+    ).await?;
+
+    Ok(Json(json!({
+        "status": "ok",
+        "playlist_id": playlist_id,
+        "skipped_keys": skipped,
+    })))
+}
+
+async fn get_playlist(
+    ctx: Ctx,
+    Extension(pool): Extension<PgPool>,
+    Path(playlist_id): Path<i32>,
+) -> Result<Json<Value>> {
+    println!("->> {:<12} - get_playlist", "Handler");
+
+    Ok(Json(json!({
+        "status": "ok",
+        "playlist": Playlist::get(&pool, user_id(&ctx)?, playlist_id).await?,
+    })))
+}
+
+async fn update_playlist(
+    ctx: Ctx,
+    Extension(pool): Extension<PgPool>,
+    Path(playlist_id): Path<i32>,
+    Json(body): Json<UpdatePlaylist>,
+) -> Result<Json<Value>> {
+    println!("->> {:<12} - update_playlist", "Handler");
+
+    // leaving the name out keeps it, sending a blank one is a mistake
+    let name = body.name.as_deref().map(str::trim);
+    if name == Some("") {
+        return Err(Error::InvalidRequest { msg: "playlist name is empty".to_string() });
+    }
+
+    let skipped = Playlist::update(
+        &pool,
+        user_id(&ctx)?,
+        playlist_id,
+        name,
+        body.description.as_ref().map(|description| description.as_deref()),
+        body.keys.as_deref(),
+    ).await?;
+
+    Ok(Json(json!({
+        "status": "ok",
+        "message": "playlist updated",
+        "skipped_keys": skipped,
+    })))
+}
+
+async fn delete_playlist(
+    ctx: Ctx,
+    Extension(pool): Extension<PgPool>,
+    Path(playlist_id): Path<i32>,
+) -> Result<Json<Value>> {
+    println!("->> {:<12} - delete_playlist", "Handler");
+
+    Playlist::delete(&pool, user_id(&ctx)?, playlist_id).await?;
+
+    Ok(Json(json!({
+        "status": "ok",
+        "message": "playlist deleted",
+    })))
+}
+
+// appends the playlist to the end of a session queue the user owns
+async fn load_playlist(
+    ctx: Ctx,
+    State(mc): State<Arc<SessionController>>,
+    Extension(pool): Extension<PgPool>,
+    Path(playlist_id): Path<i32>,
+    Json(body): Json<LoadPlaylist>,
+) -> Result<Json<Value>> {
+    println!("->> {:<12} - load_playlist", "Handler");
+
+    if !mc.check_user_own_session(ctx.id(), body.session_id.clone()).await? {
+        return Err(Error::SessionNotOwned);
+    }
+
+    let playlist = Playlist::get(&pool, user_id(&ctx)?, playlist_id).await?;
+    let session = mc.get_session(body.session_id).await?;
+
+    let items = playlist.items.unwrap_or_default();
+    for item in items.iter() {
+        session.add_to_queue(item.file_key.clone(), item.title.clone(), ctx.id(), item.duration_ms).await?;
+    }
+
+    Ok(Json(json!({
+        "status": "ok",
+        "message": "playlist loaded",
+        "added": items.len(),
+    })))
+}
+
+async fn save_queue(
+    ctx: Ctx,
+    State(mc): State<Arc<SessionController>>,
+    Extension(pool): Extension<PgPool>,
+    Json(body): Json<SaveQueue>,
+) -> Result<Json<Value>> {
+    println!("->> {:<12} - save_queue", "Handler");
+
+    if !mc.check_user_own_session(ctx.id(), body.session_id.clone()).await? {
+        return Err(Error::SessionNotOwned);
+    }
+    if body.name.trim().is_empty() {
+        return Err(Error::InvalidRequest { msg: "playlist name is empty".to_string() });
+    }
+
+    let session = mc.get_session(body.session_id).await?;
+    let keys: Vec<String> = session.get_queue().await?.into_iter().map(|item| item.file_key).collect();
+
+    let (playlist_id, skipped) = Playlist::create(
+        &pool,
+        user_id(&ctx)?,
+        body.name.trim(),
+        body.description.as_deref(),
+        &keys,
+    ).await?;
+
+    Ok(Json(json!({
+        "status": "ok",
+        "playlist_id": playlist_id,
+        "skipped_keys": skipped,
+    })))
+}
+
+async fn export_playlist(
+    ctx: Ctx,
+    Extension(pool): Extension<PgPool>,
+    Path(playlist_id): Path<i32>,
+    Query(query): Query<ExportQuery>,
+) -> Result<Response> {
+    println!("->> {:<12} - export_playlist", "Handler");
+
+    let format = PlaylistFormat::from_name(&query.format)?;
+    let playlist = Playlist::get(&pool, user_id(&ctx)?, playlist_id).await?;
+
+    // keep the file name to characters every client accepts
+    let file_name: String = playlist.name
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+        .collect();
+
+    Ok((
+        [
+            (header::CONTENT_TYPE, format.content_type().to_string()),
+            (header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}.{}\"", file_name, format.extension())),
+        ],
+        format.render(&playlist),
+    ).into_response())
+}
+
+// entries are matched to the user's files by source url, then by title,
+// the rest is downloaded and added to the playlist when the job finishes
+async fn import_playlist(
+    ctx: Ctx,
+    Extension(pool): Extension<PgPool>,
+    Extension(jobs): Extension<JobRunner>,
+    Json(body): Json<ImportPlaylist>,
+) -> Result<Json<Value>> {
+    println!("->> {:<12} - import_playlist", "Handler");
+
+    let format = PlaylistFormat::from_name(&body.format)?;
+    let (file_name, entries) = format.parse(&body.content)?;
+    if entries.is_empty() {
+        return Err(Error::PlayListParseErr { msg: "playlist has no tracks".to_string() });
+    }
+    if entries.len() > MAX_IMPORT_ENTRIES {
+        return Err(Error::InvalidRequest {
+            msg: format!("playlists can have at most {} tracks", MAX_IMPORT_ENTRIES),
+        });
+    }
+
+    let name = body.name
+        .or(file_name)
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .ok_or(Error::InvalidRequest { msg: "playlist name is empty".to_string() })?;
+
+    let uid = user_id(&ctx)?;
+    let (by_url, by_title) = library_index(&pool, uid).await?;
+
+    // work out every entry first, nothing is created when the downloads would not fit
+    let mut matched = Vec::new();
+    let mut downloads = Vec::new();
+    let mut unresolved: Vec<ImportEntry> = Vec::new();
+
+    for (position, entry) in entries.into_iter().enumerate() {
+        let position = position as i32 + 1;
+        let source = entry.url.as_deref().and_then(normalize_source_url);
+
+        let key = source.as_ref()
+            .and_then(|source| by_url.get(source))
+            .or_else(|| by_title.get(&entry.title.to_lowercase()));
+
+        match (key, source, entry.url.clone()) {
+            (Some(key), _, _) => matched.push((position, key.clone())),
+            (None, Some(_), Some(url)) => downloads.push((position, url, entry.title)),
+            _ => unresolved.push(entry),
+        }
+    }
+
+    jobs.check_headroom(&ctx.id(), downloads.len()).await?;
+
+    let (playlist_id, _) = Playlist::create(&pool, uid, &name, body.description.as_deref(), &[]).await?;
+
+    for (position, key) in matched.iter() {
+        Playlist::add_item(&pool, uid, playlist_id, *position, key).await?;
+    }
+
+    let mut job_ids = Vec::new();
+    for (position, url, title) in downloads {
+        job_ids.push(jobs.submit_for_playlist(&ctx.id(), url, title, Some((playlist_id, position))).await?);
+    }
+
+    Ok(Json(json!({
+        "status": "ok",
+        "playlist_id": playlist_id,
+        "matched": matched.len(),
+        "job_ids": job_ids,
+        "unresolved": unresolved,
+    })))
+}
+
+// the user's files keyed by normalized source url and by lowercased title
+async fn library_index(pool: &PgPool, user_id: i32) -> Result<(HashMap<String, String>, HashMap<String, String>)> {
+    let rows = sqlx::query("SELECT uuid, url, COALESCE(name, '') AS name FROM files WHERE user_id = $1 ORDER BY file_id")
+        .bind(user_id)
+        .fetch_all(pool)
+        .await
+        .map_err(|e| Error::DBError { source: e.to_string() })?;
+
+    let mut by_url = HashMap::new();
+    let mut by_title = HashMap::new();
+    for row in rows {
+        let uuid: String = row.get("uuid");
+        let url: String = row.get("url");
+        let name: String = row.get("name");
+
+        if let Some(source) = normalize_source_url(&url) {
+            by_url.entry(source).or_insert_with(|| uuid.clone());
+        }
+        if !name.is_empty() {
+            by_title.entry(name.to_lowercase()).or_insert(uuid);
+        }
+    }
+
+    Ok((by_url, by_title))
+}
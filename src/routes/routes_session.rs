@@ -6,6 +6,7 @@ use crate::models::session::{
 use crate::Ctx;
 use crate::models::peer::Listener;
 use crate::models::session::User;
+use crate::media::file_manager::FileManager;
 
 use crate::Result;
 use serde::{
@@ -102,6 +103,7 @@ pub fn routes(mc: Arc<SessionController>) -> Router {
         .route("/queue_notify", get(queue_notify))
         .route("/session_stats", get(get_session_stats))
         .route("/session_listeners", get(get_session_listeners))
+        .route("/now_playing", get(get_now_playing))
         .route("/browse", get(browse_sesions))
         .route("/leave", get(leave_session))
         .with_state(mc)
@@ -254,6 +256,7 @@ async fn add_ice(
 
 async fn get_queue(
     State(mc): State<Arc<SessionController>>,
+    Extension(pool): Extension<PgPool>,
     Query(params): Query<SessionID>,
 ) -> Result<Json<Value>> {
 
@@ -261,10 +264,17 @@ async fn get_queue(
 
     let session = mc.get_session(params.session_id).await?;
     let queue = session.get_queue().await?;
+    let (_, current_item_id) = session.get_queue_position().await?;
+
+    // metadata is keyed by file key since a key can be queued more than once
+    let keys = queue.iter().map(|item| item.file_key.clone()).collect();
+    let metadata = FileManager::get_track_metadata(&pool, keys).await?;
     
     Ok(Json(json!({
         "status": "ok",
         "queue": queue,
+        "current_item_id": current_item_id,
+        "metadata": metadata,
     })))
 }
 
@@ -307,6 +317,8 @@ async fn get_session_stats(
     let session_start_time = session.get_session_start_time().await?;
     let number_of_listeners = session.get_number_of_listeners().await?;
     let listeners = session.get_listeners().await?;
+    let paused = session.is_paused().await?;
+    let crossfade_ms = session.get_crossfade().await?;
 
     Ok(Json(json!({
         "status": "ok",
@@ -314,6 +326,8 @@ async fn get_session_stats(
         "session_start_time": session_start_time,
         "number_of_listeners": number_of_listeners,
         "listeners": listeners,
+        "paused": paused,
+        "crossfade_ms": crossfade_ms,
     })))
 }
 
@@ -334,6 +348,25 @@ async fn get_session_listeners(
     })))
 }
 
+async fn get_now_playing(
+    State(mc): State<Arc<SessionController>>,
+    Query(params): Query<SessionID>,
+) -> Result<Json<Value>> {
+    println!("->> {:<12} - get_now_playing", "Handler");
+
+    let session = mc.get_session(params.session_id).await?;
+    let now_playing = session.get_now_playing().await?;
+    let paused = session.is_paused().await?;
+
+    Ok(Json(json!({
+        "status": "ok",
+        "key": now_playing.key,
+        "elapsed_ms": now_playing.elapsed_ms,
+        "duration_ms": now_playing.duration_ms,
+        "paused": paused,
+    })))
+}
+
 async fn get_initial_queue_position(
     State(mc): State<Arc<SessionController>>,
     Query(params): Query<SessionID>,
@@ -341,11 +374,12 @@ async fn get_initial_queue_position(
     println!("->> {:<12} - get_initial_queue_position", "Handler");
 
     let session = mc.get_session(params.session_id).await?;
-    let queue_position = session.get_queue_position().await?;
+    let (queue_position, item_id) = session.get_queue_position().await?;
 
     Ok(Json(json!({
         "status": "ok",
         "index": queue_position,
+        "item_id": item_id,
     })))
 }
 
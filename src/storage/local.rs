@@ -0,0 +1,143 @@
+use crate::utils::error::Result;
+use crate::storage::{ StorageBackend, STREAM_BUFFER_CHUNKS };
+use async_trait::async_trait;
+use axum::body::Bytes;
+use std::io::SeekFrom;
+use std::path::{ Path, PathBuf };
+use tokio::io::{ AsyncReadExt, AsyncSeekExt };
+use tokio::sync::mpsc;
+
+const READ_CHUNK_SIZE: usize = 65536;
+
+/// Keeps objects as plain files in a directory, for running without AWS.
+#[derive(Clone, Debug)]
+pub struct LocalStorage {
+    dir: PathBuf,
+}
+
+impl LocalStorage {
+    pub async fn new(dir: String) -> Result<Self> {
+        if !Path::new(&dir).exists() {
+            tokio::fs::create_dir_all(&dir).await?;
+        }
+        Ok(Self { dir: PathBuf::from(dir) })
+    }
+
+    fn object_path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{}.ogg", key))
+    }
+}
+
+#[async_trait]
+impl StorageBackend for LocalStorage {
+    async fn put(&self, key: &str, file_path: &str) -> Result<()> {
+        tokio::fs::copy(file_path, self.object_path(key)).await?;
+        Ok(())
+    }
+
+    async fn get_stream(&self, key: &str) -> Result<mpsc::Receiver<Bytes>> {
+        let mut file = tokio::fs::File::open(self.object_path(key)).await?;
+        let (chunk_tx, chunk_rx) = mpsc::channel::<Bytes>(STREAM_BUFFER_CHUNKS);
+
+        tokio::spawn(async move {
+            let mut buf = vec![0u8; READ_CHUNK_SIZE];
+            loop {
+                match file.read(&mut buf).await {
+                    Ok(0) => break,
+                    Ok(n) => {
+                        if chunk_tx.send(Bytes::copy_from_slice(&buf[..n])).await.is_err() {
+                            break;
+                        }
+                    },
+                    Err(e) => {
+                        println!("Error reading from local storage: {:?}", e);
+                        break;
+                    }
+                }
+            }
+        });
+
+        Ok(chunk_rx)
+    }
+
+    async fn get_tail(&self, key: &str, len: u64) -> Result<Bytes> {
+        let mut file = tokio::fs::File::open(self.object_path(key)).await?;
+        let size = file.metadata().await?.len();
+
+        file.seek(SeekFrom::Start(size.saturating_sub(len))).await?;
+        let mut tail = Vec::new();
+        file.read_to_end(&mut tail).await?;
+
+        Ok(Bytes::from(tail))
+    }
+
+    async fn input_url(&self, key: &str) -> Result<String> {
+        Ok(self.object_path(key).to_string_lossy().to_string())
+    }
+
+    // like s3, deleting a missing object is not an error
+    async fn delete(&self, key: &str) -> Result<()> {
+        match tokio::fs::remove_file(self.object_path(key)).await {
+            Ok(_) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn exists(&self, key: &str) -> Result<bool> {
+        Ok(tokio::fs::try_exists(self.object_path(key)).await?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn temp_storage() -> (LocalStorage, PathBuf) {
+        let dir = std::env::temp_dir().join(format!("musicshare-storage-{}", uuid::Uuid::new_v4()));
+        let storage = LocalStorage::new(dir.to_string_lossy().to_string()).await.unwrap();
+        (storage, dir)
+    }
+
+    async fn read_all(storage: &LocalStorage, key: &str) -> Vec<u8> {
+        let mut chunks = storage.get_stream(key).await.unwrap();
+        let mut data = Vec::new();
+        while let Some(chunk) = chunks.recv().await {
+            data.extend_from_slice(&chunk);
+        }
+        data
+    }
+
+    #[tokio::test]
+    async fn put_get_exists_delete_round_trip() {
+        let (storage, dir) = temp_storage().await;
+
+        // larger than one read chunk so the stream has to be reassembled
+        let content: Vec<u8> = (0..READ_CHUNK_SIZE * 2 + 100).map(|i| (i % 251) as u8).collect();
+        let source = dir.join("source.ogg");
+        tokio::fs::write(&source, &content).await.unwrap();
+
+        assert!(!storage.exists("track").await.unwrap());
+        storage.put("track", &source.to_string_lossy()).await.unwrap();
+        assert!(storage.exists("track").await.unwrap());
+
+        assert_eq!(read_all(&storage, "track").await, content);
+        assert_eq!(storage.get_tail("track", 10).await.unwrap().as_ref(), &content[content.len() - 10..]);
+
+        storage.delete("track").await.unwrap();
+        assert!(!storage.exists("track").await.unwrap());
+        // deleting twice is fine, like on s3
+        storage.delete("track").await.unwrap();
+
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn get_stream_of_missing_object_fails() {
+        let (storage, dir) = temp_storage().await;
+
+        assert!(storage.get_stream("missing").await.is_err());
+
+        tokio::fs::remove_dir_all(&dir).await.unwrap();
+    }
+}
@@ -0,0 +1,55 @@
+use crate::utils::error::{ Error, Result };
+use async_trait::async_trait;
+use axum::body::Bytes;
+use std::sync::Arc;
+use std::env;
+use dotenvy::dotenv;
+use tokio::sync::mpsc;
+
+pub mod s3;
+pub mod local;
+
+pub use s3::S3Storage;
+pub use local::LocalStorage;
+
+// bounded buffer between a storage read and whoever consumes the chunks
+pub const STREAM_BUFFER_CHUNKS: usize = 16;
+
+/// Where converted files live. Every stored object is an ogg file addressed by its
+/// file key (the uuid in the files table), backends add the extension themselves.
+#[async_trait]
+pub trait StorageBackend: std::fmt::Debug + Send + Sync {
+    /// Stores the local file at file_path under key.
+    async fn put(&self, key: &str, file_path: &str) -> Result<()>;
+
+    /// Streams the object in chunks, the reading task stops once the receiver is dropped.
+    async fn get_stream(&self, key: &str) -> Result<mpsc::Receiver<Bytes>>;
+
+    /// Returns up to the last len bytes of the object.
+    async fn get_tail(&self, key: &str, len: u64) -> Result<Bytes>;
+
+    /// A location ffmpeg can read the object from directly.
+    async fn input_url(&self, key: &str) -> Result<String>;
+
+    async fn delete(&self, key: &str) -> Result<()>;
+
+    async fn exists(&self, key: &str) -> Result<bool>;
+}
+
+/// Picks the backend from STORAGE_BACKEND, "s3" (default) or "local".
+pub async fn from_env() -> Result<Arc<dyn StorageBackend>> {
+    dotenv().ok();
+
+    match env::var("STORAGE_BACKEND").unwrap_or("s3".to_string()).as_str() {
+        "s3" => {
+            let bucket = env::var("S3_BUCKET").unwrap_or("antaresmusicshare".to_string());
+            let region = env::var("S3_REGION").unwrap_or("us-east-1".to_string());
+            Ok(Arc::new(S3Storage::new(bucket, region).await))
+        },
+        "local" => {
+            let dir = env::var("LOCAL_STORAGE_DIR").unwrap_or("./storage".to_string());
+            Ok(Arc::new(LocalStorage::new(dir).await?))
+        },
+        other => Err(Error::StorageFailed { msg: format!("Unknown storage backend: {}", other) }),
+    }
+}
@@ -0,0 +1,148 @@
+use crate::utils::error::{ Error, Result };
+use crate::storage::{ StorageBackend, STREAM_BUFFER_CHUNKS };
+use async_trait::async_trait;
+use axum::body::Bytes;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+use aws_config::meta::region::RegionProviderChain;
+use aws_sdk_s3::{config::Region, Client};
+use aws_sdk_s3::presigning::PresigningConfig;
+use aws_sdk_s3::primitives::ByteStream;
+
+const PRESIGNED_URL_EXPIRY: Duration = Duration::from_secs(600);
+
+#[derive(Clone, Debug)]
+pub struct S3Storage {
+    client: Client,
+    bucket: String,
+}
+
+impl S3Storage {
+    pub async fn new(bucket: String, region: String) -> Self {
+        let region_provider = RegionProviderChain::first_try(Region::new(region));
+        let shared_config = aws_config::from_env().region(region_provider).load().await;
+
+        Self {
+            client: Client::new(&shared_config),
+            bucket,
+        }
+    }
+
+    fn object_key(key: &str) -> String {
+        format!("{}.ogg", key)
+    }
+}
+
+#[async_trait]
+impl StorageBackend for S3Storage {
+    async fn put(&self, key: &str, file_path: &str) -> Result<()> {
+        let body = ByteStream::from_path(file_path).await
+            .map_err(|e| Error::UploadFailed { msg: e.to_string() })?;
+
+        self.client
+            .put_object()
+            .bucket(&self.bucket)
+            .key(S3Storage::object_key(key))
+            .body(body)
+            .send()
+            .await
+            .map_err(|e| Error::UploadFailed { msg: e.to_string() })?;
+
+        Ok(())
+    }
+
+    async fn get_stream(&self, key: &str) -> Result<mpsc::Receiver<Bytes>> {
+        let mut object = self.client
+            .get_object()
+            .bucket(&self.bucket)
+            .key(S3Storage::object_key(key))
+            .send()
+            .await
+            .map_err(|e| Error::S3DownloadError { msg: e.to_string() })?;
+
+        let (chunk_tx, chunk_rx) = mpsc::channel::<Bytes>(STREAM_BUFFER_CHUNKS);
+
+        // pump the s3 body into the chunk buffer, stops once the reader side is dropped
+        tokio::spawn(async move {
+            loop {
+                match object.body.try_next().await {
+                    Ok(Some(bytes)) => {
+                        if chunk_tx.send(bytes).await.is_err() {
+                            break;
+                        }
+                    },
+                    Ok(None) => break,
+                    Err(e) => {
+                        println!("Error reading from s3 stream: {:?}", e);
+                        break;
+                    }
+                }
+            }
+        });
+
+        Ok(chunk_rx)
+    }
+
+    async fn get_tail(&self, key: &str, len: u64) -> Result<Bytes> {
+        let object = self.client
+            .get_object()
+            .bucket(&self.bucket)
+            .key(S3Storage::object_key(key))
+            .range(format!("bytes=-{}", len))
+            .send()
+            .await
+            .map_err(|e| Error::S3DownloadError { msg: e.to_string() })?;
+
+        let tail = object.body.collect().await
+            .map_err(|_| Error::S3DownloadError { msg: "Failed to read from s3 download streams".to_string() })?;
+
+        Ok(tail.into_bytes())
+    }
+
+    async fn input_url(&self, key: &str) -> Result<String> {
+        let config = PresigningConfig::expires_in(PRESIGNED_URL_EXPIRY)
+            .map_err(|e| Error::S3Error { msg: e.to_string() })?;
+
+        let request = self.client
+            .get_object()
+            .bucket(&self.bucket)
+            .key(S3Storage::object_key(key))
+            .presigned(config)
+            .await
+            .map_err(|e| Error::S3Error { msg: e.to_string() })?;
+
+        Ok(request.uri().to_string())
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        self.client
+            .delete_object()
+            .bucket(&self.bucket)
+            .key(S3Storage::object_key(key))
+            .send()
+            .await
+            .map_err(|e| Error::S3Error { msg: e.to_string() })?;
+
+        Ok(())
+    }
+
+    async fn exists(&self, key: &str) -> Result<bool> {
+        match self.client
+            .head_object()
+            .bucket(&self.bucket)
+            .key(S3Storage::object_key(key))
+            .send()
+            .await
+        {
+            Ok(_) => Ok(true),
+            Err(e) => {
+                if e.as_service_error().is_some_and(|e| e.is_not_found()) {
+                    Ok(false)
+                } else {
+                    Err(Error::S3Error { msg: e.to_string() })
+                }
+            }
+        }
+    }
+}
@@ -1,5 +1,6 @@
 use axum::http::StatusCode;
 use axum::response::{IntoResponse, Response};
+use serde::Serialize;
 pub type Result<T> = core::result::Result<T, Error>;
 pub type ClientResult<T> = core::result::Result<T, ClientError>;
 
@@ -13,6 +14,8 @@ pub enum Error {
     LocalDescriptionMissing,
     StdIoError { source: String },
     FileTooLarge { size: u64, limit: u64 },
+    QuotaExceeded { kind: String, limit: i64 },
+    UnsupportedFormat { format: String },
     InvalidURL { url: String },
     LiveStreamNotSupported { url: String },
     PlayListParseErr { msg: String },
@@ -20,13 +23,21 @@ pub enum Error {
     AuthFailNoToken,
     AuthFailInvalidToken,
     AuthFailCtxNotFound,
+    UserExists,
     DBError { source: String },
     ContentNotFound { msg: String },
+    JobNotFound { id: i32 },
+    PlaylistNotFound { id: i32 },
+    JobFinished { id: i32 },
+    JobCancelled,
     DownloadFailed { url: String },
+    // yt-dlp failed on the network or a server error, the same download may work later
+    DownloadInterrupted { url: String },
     ConversionFailed { url: String },
     DatabaseWriteError { msg: String },
     UploadFailed { msg: String },
     QueueError { msg: String },
+    InvalidRequest { msg: String },
     DuplicateContent { msg: String },
 
     S3DownloadError { msg: String },
@@ -34,6 +45,7 @@ pub enum Error {
     ResetFileError { msg: String },
 
     S3Error { msg: String },
+    StorageFailed { msg: String },
 
     SSEError { msg: String },
 
@@ -43,32 +55,158 @@ pub enum Error {
     SessionNotOwned,
     SessionFull,
     SessionError { msg: String },
+
+    ConfigError { msg: String },
 }
 
-#[derive(Clone, Debug)]
+/// What a client is allowed to see of an error, serialized as the "code" and
+/// "details" of the json error body. Internal sources never make it in here.
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "code", content = "details", rename_all = "SCREAMING_SNAKE_CASE")]
 pub enum ClientError {
+    LoginFail,
+    NoAuth,
+    UserExists,
+    SessionNotOwned,
+    SessionNotFound { id: String },
+    PeerNotFound,
+    ContentNotFound,
+    JobNotFound { id: i32 },
+    PlaylistNotFound { id: i32 },
+    JobFinished { id: i32 },
+    JobCancelled,
     SessionExists,
     SessionFull,
+    DuplicateContent,
+    FileTooLarge { size: u64, limit: u64 },
+    QuotaExceeded { kind: String, limit: i64 },
+    UnsupportedFormat { format: String },
+    InvalidUrl { url: String },
+    // msg is only set from Error::InvalidRequest, whose messages are written for clients
+    InvalidRequest {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        msg: Option<String>,
+    },
+    DownloadFailed { url: String },
+    StorageUnavailable,
+    ServiceError,
 }
 
+impl ClientError {
+    // taken from the serialized form, so the "code" field and this never disagree
+    pub fn code(&self) -> String {
+        serde_json::to_value(self)
+            .ok()
+            .and_then(|json| json["code"].as_str().map(str::to_string))
+            .unwrap_or_default()
+    }
 
-impl IntoResponse for Error {
-    fn into_response(self) -> Response {
-        println!("->> {:<12} - {self:?}", "INTO_RES");
+    pub fn message(&self) -> &'static str {
+        match self {
+            ClientError::LoginFail => "Login failed",
+            ClientError::NoAuth => "Missing or invalid credentials",
+            ClientError::UserExists => "User already exists",
+            ClientError::SessionNotOwned => "Session is owned by another user",
+            ClientError::SessionNotFound { .. } => "Session not found",
+            ClientError::PeerNotFound => "Peer connection not found",
+            ClientError::ContentNotFound => "Content not found",
+            ClientError::JobNotFound { .. } => "Job not found",
+            ClientError::PlaylistNotFound { .. } => "Playlist not found",
+            ClientError::JobFinished { .. } => "Job already finished",
+            ClientError::JobCancelled => "Job was cancelled",
+            ClientError::SessionExists => "User already has a session",
+            ClientError::SessionFull => "Session is full",
+            ClientError::DuplicateContent => "Content already exists",
+            ClientError::FileTooLarge { .. } => "File is too large",
+            ClientError::QuotaExceeded { .. } => "Storage quota exceeded",
+            ClientError::UnsupportedFormat { .. } => "Unsupported file format",
+            ClientError::InvalidUrl { .. } => "Invalid or unsupported url",
+            ClientError::InvalidRequest { .. } => "Invalid request",
+            ClientError::DownloadFailed { .. } => "Download failed",
+            ClientError::StorageUnavailable => "Storage is unavailable",
+            ClientError::ServiceError => "Service error",
+        }
+    }
+}
 
-        // return a response with the error code and message
-        // return the error type and message
-        (StatusCode::INTERNAL_SERVER_ERROR, format!("{:?}", self)).into_response()
+impl Error {
+    // failures that may go away when the same work is tried again
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            Error::StdIoError { .. }
+                | Error::DownloadInterrupted { .. }
+                | Error::ConversionFailed { .. }
+                | Error::UploadFailed { .. }
+                | Error::S3DownloadError { .. }
+                | Error::S3LoadFileError { .. }
+                | Error::S3Error { .. }
+                | Error::StorageFailed { .. }
+        )
+    }
+
+    pub fn client_status_and_error(&self) -> (StatusCode, ClientError) {
+        match self {
+            Error::LoginFail => (StatusCode::UNAUTHORIZED, ClientError::LoginFail),
+
+            Error::AuthFailNoToken
+            | Error::AuthFailInvalidToken
+            | Error::AuthFailCtxNotFound => (StatusCode::UNAUTHORIZED, ClientError::NoAuth),
+
+            Error::UserExists => (StatusCode::CONFLICT, ClientError::UserExists),
+
+            Error::SessionNotOwned => (StatusCode::FORBIDDEN, ClientError::SessionNotOwned),
+
+            Error::SessionNotFound { id } => (StatusCode::NOT_FOUND, ClientError::SessionNotFound { id: id.clone() }),
+            Error::SessionDeleteFailIdNotFound { id } => (StatusCode::NOT_FOUND, ClientError::SessionNotFound { id: id.to_string() }),
+            Error::PeerConnectionNotFound { .. } => (StatusCode::NOT_FOUND, ClientError::PeerNotFound),
+            Error::ContentNotFound { .. } => (StatusCode::NOT_FOUND, ClientError::ContentNotFound),
+            Error::JobNotFound { id } => (StatusCode::NOT_FOUND, ClientError::JobNotFound { id: *id }),
+            Error::PlaylistNotFound { id } => (StatusCode::NOT_FOUND, ClientError::PlaylistNotFound { id: *id }),
+            Error::JobFinished { id } => (StatusCode::CONFLICT, ClientError::JobFinished { id: *id }),
+            Error::JobCancelled => (StatusCode::CONFLICT, ClientError::JobCancelled),
+
+            Error::SessionExists => (StatusCode::CONFLICT, ClientError::SessionExists),
+            Error::SessionFull => (StatusCode::CONFLICT, ClientError::SessionFull),
+            Error::DuplicateContent { .. } => (StatusCode::CONFLICT, ClientError::DuplicateContent),
+
+            Error::FileTooLarge { size, limit } => (StatusCode::PAYLOAD_TOO_LARGE, ClientError::FileTooLarge { size: *size, limit: *limit }),
+            // a policy limit of the user, not the server running out of space
+            Error::QuotaExceeded { kind, limit } => {
+                let status = if kind == "bytes" { StatusCode::PAYLOAD_TOO_LARGE } else { StatusCode::FORBIDDEN };
+                (status, ClientError::QuotaExceeded { kind: kind.clone(), limit: *limit })
+            },
+            Error::UnsupportedFormat { format } => (StatusCode::UNSUPPORTED_MEDIA_TYPE, ClientError::UnsupportedFormat { format: format.clone() }),
+
+            Error::InvalidURL { url }
+            | Error::LiveStreamNotSupported { url } => (StatusCode::BAD_REQUEST, ClientError::InvalidUrl { url: url.clone() }),
+            Error::InvalidRequest { msg } => (StatusCode::BAD_REQUEST, ClientError::InvalidRequest { msg: Some(msg.clone()) }),
+            // these can carry tool output, the client only learns the request was bad
+            Error::PlayListParseErr { .. }
+            | Error::QueueError { .. } => (StatusCode::BAD_REQUEST, ClientError::InvalidRequest { msg: None }),
+
+            Error::DownloadFailed { url }
+            | Error::DownloadInterrupted { url }
+            | Error::ConversionFailed { url } => (StatusCode::BAD_GATEWAY, ClientError::DownloadFailed { url: url.clone() }),
+
+            Error::S3DownloadError { .. }
+            | Error::S3LoadFileError { .. }
+            | Error::S3Error { .. }
+            | Error::StorageFailed { .. } => (StatusCode::BAD_GATEWAY, ClientError::StorageUnavailable),
+
+            _ => (StatusCode::INTERNAL_SERVER_ERROR, ClientError::ServiceError),
+        }
     }
 }
 
-impl IntoResponse for ClientError {
+impl IntoResponse for Error {
     fn into_response(self) -> Response {
         println!("->> {:<12} - {self:?}", "INTO_RES");
 
-        // return a response with the error code and message
-        // return the error type and message
-        (StatusCode::BAD_REQUEST, format!("{:?}", self)).into_response()
+        // placeholder response, main_response_mapper turns it into the client json body
+        let mut response = StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        response.extensions_mut().insert(self);
+        response
     }
 }
 
@@ -96,4 +234,39 @@ impl From<sqlx::Error> for Error {
         }
     }
 
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn code_is_the_serialized_code() {
+        let errors = [
+            (ClientError::NoAuth, "NO_AUTH"),
+            (ClientError::InvalidUrl { url: "x".to_string() }, "INVALID_URL"),
+            (ClientError::QuotaExceeded { kind: "files".to_string(), limit: 1 }, "QUOTA_EXCEEDED"),
+            (ClientError::InvalidRequest { msg: None }, "INVALID_REQUEST"),
+            (ClientError::ServiceError, "SERVICE_ERROR"),
+        ];
+        for (error, code) in errors {
+            let json = serde_json::to_value(&error).unwrap();
+            assert_eq!(json["code"], code);
+            assert_eq!(error.code(), code);
+        }
+    }
+
+    #[test]
+    fn invalid_request_message_reaches_the_client() {
+        let error = Error::InvalidRequest { msg: "sort must be date, name or duration".to_string() };
+        let (status, client_error) = error.client_status_and_error();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+
+        let json = serde_json::to_value(&client_error).unwrap();
+        assert_eq!(json["details"]["msg"], "sort must be date, name or duration");
+
+        // other bad requests keep their internal message to themselves
+        let error = Error::PlayListParseErr { msg: "yt-dlp: ERROR: something internal".to_string() };
+        let json = serde_json::to_value(&error.client_status_and_error().1).unwrap();
+        assert!(json["details"].get("msg").is_none());
+    }
+}
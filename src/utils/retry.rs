@@ -0,0 +1,83 @@
+use crate::utils::error::{ Error, Result };
+use rand::Rng;
+use std::env;
+use std::future::Future;
+use std::time::Duration;
+use dotenvy::dotenv;
+
+/// How often a stage is attempted and how long to wait in between, the delay doubles
+/// with every attempt up to max_delay and gets up to half of it added as jitter.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl RetryPolicy {
+    /// Reads RETRY_<STAGE>_ATTEMPTS, RETRY_<STAGE>_DELAY_MS and RETRY_<STAGE>_MAX_DELAY_MS.
+    pub fn from_env(stage: &str, max_attempts: u32, base_delay_ms: u64) -> Self {
+        dotenv().ok();
+
+        let var = |name: &str| env::var(format!("RETRY_{}_{}", stage, name)).ok().and_then(|v| v.parse::<u64>().ok());
+
+        Self {
+            max_attempts: var("ATTEMPTS").map(|v| v as u32).unwrap_or(max_attempts).max(1),
+            base_delay: Duration::from_millis(var("DELAY_MS").unwrap_or(base_delay_ms)),
+            max_delay: Duration::from_millis(var("MAX_DELAY_MS").unwrap_or(30000)),
+        }
+    }
+
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let exp = self.base_delay.saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)));
+        let delay = exp.min(self.max_delay);
+        let jitter = rand::thread_rng().gen_range(0..=delay.as_millis() as u64 / 2);
+        delay + Duration::from_millis(jitter)
+    }
+
+    /// Runs f until it succeeds, fails with an error that is not transient or runs out of
+    /// attempts. on_attempt is called with the attempt number before every attempt.
+    pub async fn run<T, F, Fut, A, AFut>(&self, stage: &str, mut f: F, mut on_attempt: A) -> Result<T>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<T>>,
+        A: FnMut(u32) -> AFut,
+        AFut: Future<Output = ()>,
+    {
+        let mut attempt = 1;
+        loop {
+            on_attempt(attempt).await;
+
+            match f().await {
+                Ok(value) => return Ok(value),
+                Err(e) if attempt < self.max_attempts && e.is_transient() => {
+                    let delay = self.delay(attempt);
+                    println!("->> {:<12} - {} attempt {} failed, retrying in {:?}: {:?}", "Retry", stage, attempt, delay, e);
+                    tokio::time::sleep(delay).await;
+                    attempt += 1;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+/// One policy per stage of getting a file into storage.
+#[derive(Clone, Debug)]
+pub struct RetryPolicies {
+    pub probe: RetryPolicy,
+    pub download: RetryPolicy,
+    pub conversion: RetryPolicy,
+    pub upload: RetryPolicy,
+}
+
+impl RetryPolicies {
+    pub fn from_env() -> Self {
+        Self {
+            probe: RetryPolicy::from_env("PROBE", 3, 500),
+            download: RetryPolicy::from_env("DOWNLOAD", 3, 2000),
+            conversion: RetryPolicy::from_env("CONVERSION", 2, 500),
+            upload: RetryPolicy::from_env("UPLOAD", 5, 1000),
+        }
+    }
+}